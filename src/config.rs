@@ -1,6 +1,10 @@
 use serde::{Deserialize, Serialize};
 use std::path::{Path, PathBuf};
 use std::io::Write;
+use std::net::IpAddr;
+
+use crate::event::EventBuilderConfig;
+use crate::run::RunPattern;
 
 #[derive(Debug)]
 pub enum ConfigError {
@@ -33,23 +37,262 @@ impl std::error::Error for ConfigError {
 
 }
 
+/**
+    A data subdirectory of a CoMPASS run. CoMPASS writes UNFILTERED, FILTERED, and RAW data to
+    subdirectories of the same name; any other name is taken as a custom subdirectory.
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(from = "String", into = "String")]
+pub enum DataDirectory {
+    Unfiltered,
+    Filtered,
+    Raw,
+    Custom(String)
+}
+
+impl DataDirectory {
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Unfiltered => "UNFILTERED",
+            Self::Filtered => "FILTERED",
+            Self::Raw => "RAW",
+            Self::Custom(name) => name
+        }
+    }
+}
+
+impl From<String> for DataDirectory {
+    fn from(value: String) -> Self {
+        match value.as_str() {
+            "UNFILTERED" => Self::Unfiltered,
+            "FILTERED" => Self::Filtered,
+            "RAW" => Self::Raw,
+            _ => Self::Custom(value)
+        }
+    }
+}
+
+impl From<DataDirectory> for String {
+    fn from(value: DataDirectory) -> Self {
+        value.name().to_string()
+    }
+}
+
+/**
+    How the project directory is watched. Native uses OS file events; Poll periodically checks the files,
+    which is needed when the data is written by another machine to a network share.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WatchMode {
+    #[default]
+    Native,
+    Poll
+}
+
+/**
+    What to do with a run which is already in progress when ritual starts.
+    Off: wait for the next run. Beginning: stream its files from the start. End: stream only data written from now on.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AttachMode {
+    Off,
+    #[default]
+    Beginning,
+    End
+}
+
+/**
+    When data is read from the files of the active run. Changed: on every write event, only the file which changed.
+    Batch: all files together on the tail timer only, ignoring write events (fewer, larger messages with many channels).
+    In both modes the tail timer reads any file with new data.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReadMode {
+    #[default]
+    Changed,
+    Batch
+}
+
+/**
+    What the server does with new data for a client whose queue is full (a client reading slower than the data arrives).
+    DropOldest: discard the oldest queued data. DropNewest: discard the new data. Disconnect: close the connection.
+    Only hit, event, and heartbeat data is discarded; run control, metadata, and rejection frames are always delivered,
+    unless a client falls so far behind that it is closed.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    #[default]
+    DropOldest,
+    DropNewest,
+    Disconnect
+}
+
+/**
+    What the server does with a new client when it already has max_connections clients.
+    Reject: turn the new client away. EvictIdle: close the client which has gone longest without taking data
+    (the oldest client if all are keeping up) to make room. Allow-listed clients are never evicted.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AdmissionPolicy {
+    #[default]
+    Reject,
+    EvictIdle
+}
+
+/**
+    Limits of the catch-up buffer of recent data sent to clients which connect part way through a run,
+    read from the config. The oldest data is dropped once any of the limits is passed.
+    max_messages is a number of frames, max_bytes a total frame size, and max_age_ms an age in milliseconds.
+ */
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CatchUpConfig {
+    #[serde(default = "default_catch_up_messages")]
+    pub max_messages: usize,
+    #[serde(default = "default_catch_up_bytes")]
+    pub max_bytes: usize,
+    #[serde(default)]
+    pub max_age_ms: Option<u64>
+}
+
+fn default_catch_up_messages() -> usize {
+    1000
+}
+
+//64 MiB
+fn default_catch_up_bytes() -> usize {
+    64 << 20
+}
+
+/**
+    data_directories are the subdirectories of each run to read data from. Each is streamed separately.
+    time_ordered switches the data stream from per-file blobs to hits merged across all files in timestamp order.
+    hold_back_window is how far (in timestamp units, ps) the merge waits behind the newest hit for slower files.
+    event_builder enables streaming built events (in addition to the hit data) when given.
+    rescan_interval_ms is how often the active run is checked for new data files, in milliseconds.
+    tail_interval_ms is how often the data files are checked for new data without a file event, in milliseconds.
+    read_mode selects reading the changed file on each write event, or all files on the tail timer.
+    run_timeout_ms ends the active run once its files have not grown for this long, in milliseconds (null, the default, to disable).
+    The run resumes if its files grow again before the next run starts.
+    run_stop_files are the names of files whose creation in the run directory marks the end of the run (none by default).
+    run_pattern is a regex the run directory names must match, capturing the run number (default ^run_(\d+)$).
+    attach_mode is how to treat the most recent run if it is in progress at startup.
+    watch_mode selects OS file events or polling, with poll_interval_ms as the polling period in milliseconds.
+    client_queue_size is how many messages are queued for each client before overflow_policy applies.
+    max_connections is the number of clients the server serves at once, with reserved_connections of them kept
+    for clients connecting from an address in allow_list. admission_policy applies when there is no free slot.
+    catch_up enables sending clients which connect part way through a run the run metadata and its recent data when given.
+    heartbeat_interval_ms is how often a Heartbeat is sent to the clients, in milliseconds (null to disable).
+    heartbeat_timeout_ms closes clients which have not acked a Heartbeat for this long, in milliseconds (null to disable,
+    in which case clients do not need to ack).
+ */
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Config {
     pub server_address: String,
-    pub project_directory: PathBuf
+    pub project_directory: PathBuf,
+    #[serde(default = "default_data_directories")]
+    pub data_directories: Vec<DataDirectory>,
+    #[serde(default)]
+    pub time_ordered: bool,
+    #[serde(default = "default_hold_back_window")]
+    pub hold_back_window: u64,
+    #[serde(default)]
+    pub event_builder: Option<EventBuilderConfig>,
+    #[serde(default = "default_rescan_interval_ms")]
+    pub rescan_interval_ms: u64,
+    #[serde(default = "default_tail_interval_ms")]
+    pub tail_interval_ms: u64,
+    #[serde(default)]
+    pub read_mode: ReadMode,
+    #[serde(default = "default_run_timeout_ms")]
+    pub run_timeout_ms: Option<u64>,
+    #[serde(default = "default_run_stop_files")]
+    pub run_stop_files: Vec<String>,
+    #[serde(default)]
+    pub run_pattern: RunPattern,
+    #[serde(default)]
+    pub attach_mode: AttachMode,
+    #[serde(default)]
+    pub watch_mode: WatchMode,
+    #[serde(default = "default_poll_interval_ms")]
+    pub poll_interval_ms: u64,
+    #[serde(default = "default_client_queue_size")]
+    pub client_queue_size: usize,
+    #[serde(default)]
+    pub overflow_policy: OverflowPolicy,
+    #[serde(default = "default_max_connections")]
+    pub max_connections: usize,
+    #[serde(default)]
+    pub reserved_connections: usize,
+    #[serde(default)]
+    pub allow_list: Vec<IpAddr>,
+    #[serde(default)]
+    pub admission_policy: AdmissionPolicy,
+    #[serde(default)]
+    pub catch_up: Option<CatchUpConfig>,
+    #[serde(default = "default_heartbeat_interval_ms")]
+    pub heartbeat_interval_ms: Option<u64>,
+    #[serde(default)]
+    pub heartbeat_timeout_ms: Option<u64>
+}
+
+fn default_data_directories() -> Vec<DataDirectory> {
+    vec![DataDirectory::Unfiltered]
+}
+
+//1 ms
+fn default_hold_back_window() -> u64 {
+    1_000_000_000
+}
+
+fn default_rescan_interval_ms() -> u64 {
+    1000
+}
+
+fn default_tail_interval_ms() -> u64 {
+    500
+}
+
+//Beam pauses can be long, so runs only end on a new run, a stop file, or removal unless a timeout is set
+fn default_run_timeout_ms() -> Option<u64> {
+    None
+}
+
+//When CoMPASS writes its files is not pinned down, so by default no file ends a run
+fn default_run_stop_files() -> Vec<String> {
+    vec![]
+}
+
+fn default_poll_interval_ms() -> u64 {
+    500
+}
+
+fn default_client_queue_size() -> usize {
+    64
+}
+
+fn default_max_connections() -> usize {
+    5
+}
+
+fn default_heartbeat_interval_ms() -> Option<u64> {
+    Some(1000)
 }
 
 pub fn read_config_file(filepath: &Path) -> Result<Config, ConfigError> {
     let yaml_str = std::fs::read_to_string(filepath)?;
 
-    return Ok(serde_yaml::from_str::<Config>(&yaml_str)?);
+    Ok(serde_yaml::from_str::<Config>(&yaml_str)?)
 }
 
-#[allow(dead_code)]
 pub fn write_config_to_file(config: &Config, filepath: &Path) -> Result<(), ConfigError> {
     let mut handle = std::fs::File::create(filepath)?;
     let yaml_str = serde_yaml::to_string(&config)?;
-    handle.write(yaml_str.as_bytes())?;
+    handle.write_all(yaml_str.as_bytes())?;
 
     Ok(())
 }
\ No newline at end of file
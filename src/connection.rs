@@ -0,0 +1,605 @@
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use bytes::Bytes;
+
+use crate::config::{Config, OverflowPolicy};
+use crate::message::{Message, MessageError, MessageKind, convert_messages_to_bytes};
+use crate::server::{RunCache, ServerError};
+use crate::subscription::{Command, Subscription};
+
+//How long the server waits for clients to take the rest of their queued data when shutting down
+pub const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+//How long a rejected client has to take the rejection frame before the connection is closed anyway
+pub const REJECT_TIMEOUT: Duration = Duration::from_secs(1);
+//Largest frame a client may send; commands are small, so anything larger is not a command
+pub const MAX_COMMAND_SIZE: usize = 4096;
+//How far control frames may be queued over capacity; a client which falls this far behind is closed
+const MAX_CONTROL_OVERFLOW: usize = 16;
+
+/**
+    Options for client connections, taken from the Config. If heartbeat_timeout is given, a client which
+    has not acked a heartbeat within that time of it being sent is closed.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionOptions {
+    pub queue_size: usize,
+    pub overflow_policy: OverflowPolicy,
+    pub heartbeat_timeout: Option<Duration>
+}
+
+impl ConnectionOptions {
+    pub fn new(config: &Config) -> Self {
+        ConnectionOptions {
+            queue_size: config.client_queue_size.max(1),
+            overflow_policy: config.overflow_policy,
+            heartbeat_timeout: config.heartbeat_timeout_ms.map(Duration::from_millis)
+        }
+    }
+}
+
+impl Default for ConnectionOptions {
+    fn default() -> Self {
+        ConnectionOptions { queue_size: 64, overflow_policy: OverflowPolicy::default(), heartbeat_timeout: None }
+    }
+}
+
+//What happened to data given to an OutboundQueue
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PushResult {
+    Queued,
+    Dropped, //Queued, but data was discarded to make room (or the data itself was discarded)
+    Closed
+}
+
+//What queued data holds, which decides what the overflow policy may discard
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Contents {
+    Data, //Hit and event data
+    Heartbeat, //Only heartbeats; only the latest one is kept
+    Control //Any other frame (RunStart, RunStop, Metadata, Rejected, ...), which is never discarded
+}
+
+#[derive(Debug, Default)]
+struct QueueState {
+    data: VecDeque<(Bytes, Contents)>, //Queued data, and what it holds
+    closed: bool, //No more data will be written
+    finished: bool, //No more data will be queued; what is queued is still written
+    last_write: Option<Instant> //When data was last written to the client
+}
+
+//Check what the frames of the data are
+fn contents_of(mut data: &[u8]) -> Contents {
+    let mut heartbeats = false;
+    let mut other = false;
+    while let Ok(header) = Message::peek(data) {
+        match header.kind {
+            MessageKind::Hits | MessageKind::DecodedHits | MessageKind::Events => other = true,
+            MessageKind::Heartbeat => heartbeats = true,
+            _ => return Contents::Control
+        }
+        data = &data[header.frame_size.min(data.len())..];
+    }
+    match heartbeats && !other {
+        true => Contents::Heartbeat,
+        false => Contents::Data
+    }
+}
+
+/*
+    Bounded queue of data waiting to be written to a client. The ServerSender pushes to it without waiting,
+    and the writer task of the connection pops from it. There is only ever one writer, so notify_one
+    never loses a wakeup: the permit is stored if the writer is not waiting yet.
+    Data holding control frames is never dropped by the overflow policy: other data is dropped in its place,
+    and if the queue holds nothing else the control frames are queued over capacity, up to MAX_CONTROL_OVERFLOW,
+    after which the client is closed. A new heartbeat replaces one which is still queued.
+ */
+#[derive(Debug)]
+struct OutboundQueue {
+    state: Mutex<QueueState>,
+    ready: Notify,
+    capacity: usize,
+    policy: OverflowPolicy
+}
+
+impl OutboundQueue {
+
+    fn new(options: &ConnectionOptions) -> Self {
+        OutboundQueue { state: Mutex::new(QueueState::default()), ready: Notify::new(), capacity: options.queue_size.max(1), policy: options.overflow_policy }
+    }
+
+    fn push(&self, data: Bytes) -> PushResult {
+        let mut state = self.state.lock().unwrap();
+        if state.closed || state.finished {
+            return PushResult::Closed;
+        }
+
+        let contents = contents_of(&data);
+        if contents == Contents::Heartbeat {
+            state.data.retain(|(_, queued)| *queued != Contents::Heartbeat);
+        }
+        let mut result = PushResult::Queued;
+        if state.data.len() >= self.capacity {
+            let oldest_data = state.data.iter().position(|(_, queued)| *queued != Contents::Control);
+            match self.policy {
+                OverflowPolicy::DropOldest | OverflowPolicy::DropNewest if contents == Contents::Control => {
+                    if let Some(index) = oldest_data {
+                        state.data.remove(index);
+                        result = PushResult::Dropped;
+                    } else if state.data.len() >= self.capacity + MAX_CONTROL_OVERFLOW {
+                        state.closed = true;
+                        state.data.clear();
+                        drop(state);
+                        self.ready.notify_one();
+                        return PushResult::Closed;
+                    }
+                },
+                OverflowPolicy::DropOldest => match oldest_data {
+                    Some(index) => {
+                        state.data.remove(index);
+                        result = PushResult::Dropped;
+                    },
+                    None => return PushResult::Dropped
+                },
+                OverflowPolicy::DropNewest => return PushResult::Dropped,
+                OverflowPolicy::Disconnect => {
+                    state.closed = true;
+                    state.data.clear();
+                    drop(state);
+                    self.ready.notify_one();
+                    return PushResult::Closed;
+                }
+            }
+        }
+        state.data.push_back((data, contents));
+        drop(state);
+        self.ready.notify_one();
+        result
+    }
+
+    //Wait for the next data to write. None once the queue is closed, or finished and empty.
+    async fn pop(&self) -> Option<Bytes> {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.closed {
+                    return None;
+                }
+                if let Some((data, _)) = state.data.pop_front() {
+                    return Some(data);
+                }
+                if state.finished {
+                    return None;
+                }
+            }
+            self.ready.notified().await;
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.state.lock().unwrap().data.len()
+    }
+
+    fn is_closed(&self) -> bool {
+        self.state.lock().unwrap().closed
+    }
+
+    fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        state.data.clear();
+        drop(state);
+        self.ready.notify_one();
+    }
+
+    fn finish(&self) {
+        self.state.lock().unwrap().finished = true;
+        self.ready.notify_one();
+    }
+
+    //Replace whatever is queued with this last data
+    fn finish_with(&self, data: Bytes) {
+        let mut state = self.state.lock().unwrap();
+        if !state.closed {
+            state.data.clear();
+            state.data.push_back((data, Contents::Control));
+        }
+        state.finished = true;
+        drop(state);
+        self.ready.notify_one();
+    }
+
+    fn wrote(&self) {
+        self.state.lock().unwrap().last_write = Some(Instant::now());
+    }
+
+    fn last_write(&self) -> Option<Instant> {
+        self.state.lock().unwrap().last_write
+    }
+}
+
+//Write queued data to the client until the queue is done or the client goes away
+async fn write_queued(mut stream: OwnedWriteHalf, address: SocketAddr, queue: Arc<OutboundQueue>) {
+    while let Some(data) = queue.pop().await {
+        if let Err(e) = stream.write_all(&data).await {
+            tracing::info!("Connection {} recieved the following error: {}. Closing connection.", address, e);
+            break;
+        }
+        queue.wrote();
+    }
+    queue.close();
+}
+
+//Read commands from the client until it stops sending. A client which sends something other than frames, or a frame larger than MAX_COMMAND_SIZE, is closed.
+async fn read_commands(mut stream: OwnedReadHalf, address: SocketAddr, queue: Arc<OutboundQueue>, subscription: Arc<Mutex<Subscription>>, run_cache: Arc<RunCache>, acked: Arc<AtomicU64>) {
+    let mut buffer: Vec<u8> = vec![];
+    let mut chunk = [0; 1024];
+    loop {
+        match stream.read(&mut chunk).await {
+            Ok(0) => return,
+            Ok(n) => buffer.extend_from_slice(&chunk[..n]),
+            Err(e) => {
+                tracing::info!("Connection {} recieved the following error: {}. Closing connection.", address, e);
+                queue.close();
+                return;
+            }
+        }
+
+        loop {
+            //Checked as soon as the header is in, so that an oversized frame is never buffered
+            if let Ok(header) = Message::peek(&buffer) {
+                if header.frame_size > MAX_COMMAND_SIZE {
+                    tracing::warn!("Connection {} sent a frame of {} bytes, larger than any command. Closing connection.", address, header.frame_size);
+                    queue.close();
+                    return;
+                }
+            }
+            let message = match Message::decode(&buffer) {
+                Ok((message, size)) => {
+                    buffer.drain(..size);
+                    message
+                },
+                Err(MessageError::Incomplete(_)) => break,
+                Err(e) => {
+                    tracing::warn!("Connection {} sent an invalid frame: {}. Closing connection.", address, e);
+                    queue.close();
+                    return;
+                }
+            };
+
+            match Command::from_message(&message) {
+                Ok(Command::RequestMetadata) => {
+                    let frames = run_cache.run_frames();
+                    if !frames.is_empty() {
+                        queue.push(frames);
+                    }
+                },
+                Ok(Command::HeartbeatAck(sequence)) => {
+                    acked.fetch_max(sequence, Ordering::Relaxed);
+                },
+                Ok(command) => {
+                    tracing::info!("Connection {} sent command {:?}", address, command);
+                    subscription.lock().unwrap().apply(&command);
+                },
+                Err(e) => tracing::warn!("Connection {} sent an unusable command: {}", address, e)
+            }
+        }
+    }
+}
+
+/**
+    A connection is an abstraction of a TCP stream. The server writes data to the client,
+    filtered by the client's Subscription, and reads commands sent by the client.
+    Each connection has its own task writing to the stream from a bounded queue, so that a
+    slow client only ever holds up itself. When the queue is full the overflow policy applies.
+    Heartbeats sent to the client are tracked until it acks them, if the options give a heartbeat timeout.
+ */
+#[derive(Debug)]
+pub struct Connection {
+    address: SocketAddr,
+    queue: Arc<OutboundQueue>,
+    subscription: Arc<Mutex<Subscription>>,
+    writer: JoinHandle<()>,
+    reader: JoinHandle<()>,
+    connected_at: Instant,
+    overflowing: bool,
+    heartbeat_timeout: Option<Duration>,
+    acked: Arc<AtomicU64>, //Latest heartbeat sequence acked by the client
+    unacked: VecDeque<(u64, Instant)> //Heartbeats sent but not yet acked, oldest first
+}
+
+impl Connection {
+
+    /**
+        Start writing to and reading from the stream. Metadata requests are answered from the run_cache.
+        Must be called from within a tokio runtime.
+     */
+    pub fn new(stream: TcpStream, addr: SocketAddr, options: &ConnectionOptions, run_cache: Arc<RunCache>) -> Self {
+        let (read_half, write_half) = stream.into_split();
+        let queue = Arc::new(OutboundQueue::new(options));
+        let subscription = Arc::new(Mutex::new(Subscription::default()));
+        let acked = Arc::new(AtomicU64::new(0));
+        let writer = tokio::spawn(write_queued(write_half, addr, queue.clone()));
+        let reader = tokio::spawn(read_commands(read_half, addr, queue.clone(), subscription.clone(), run_cache, acked.clone()));
+        Connection {
+            address: addr,
+            queue,
+            subscription,
+            writer,
+            reader,
+            connected_at: Instant::now(),
+            overflowing: false,
+            heartbeat_timeout: options.heartbeat_timeout,
+            acked,
+            unacked: VecDeque::new()
+        }
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    pub fn is_open(&self) -> bool {
+        !self.queue.is_closed()
+    }
+
+    ///When the client last took data, or when it connected if it has not taken any yet
+    pub fn idle_since(&self) -> Instant {
+        self.queue.last_write().unwrap_or(self.connected_at)
+    }
+
+    ///Number of messages waiting to be written to the client
+    pub fn queued(&self) -> usize {
+        self.queue.len()
+    }
+
+    ///What the client has asked to receive
+    pub fn subscription(&self) -> Subscription {
+        self.subscription.lock().unwrap().clone()
+    }
+
+    ///Queue the part of the data the client is subscribed to, without waiting for it to be written
+    pub fn send(&mut self, data: &Bytes) -> Result<(), ServerError> {
+        let data = self.subscription.lock().unwrap().filter(data);
+        if data.is_empty() {
+            return match self.is_open() {
+                true => Ok(()),
+                false => Err(ServerError::ConnectionClosed(self.address.to_string()))
+            };
+        }
+        self.queue_data(data)
+    }
+
+    /**
+        Queue a Heartbeat frame (which every client gets, whatever its subscription). If there is a heartbeat timeout
+        and the oldest heartbeat the client has not acked was sent longer ago than that, the connection is closed instead.
+     */
+    pub fn send_heartbeat(&mut self, sequence: u64, data: &Bytes) -> Result<(), ServerError> {
+        if let Some(timeout) = self.heartbeat_timeout {
+            let now = Instant::now();
+            let acked = self.acked.load(Ordering::Relaxed);
+            while self.unacked.front().is_some_and(|(sent, _)| *sent <= acked) {
+                self.unacked.pop_front();
+            }
+            if self.unacked.front().is_some_and(|(_, sent_at)| now.duration_since(*sent_at) > timeout) {
+                tracing::warn!("Connection {} has not acked a heartbeat in {:?}", self.address, timeout);
+                self.queue.close();
+                self.writer.abort();
+                return Err(ServerError::HeartbeatTimeout(self.address.to_string()));
+            }
+            self.unacked.push_back((sequence, now));
+        }
+        self.queue_data(data.clone())
+    }
+
+    fn queue_data(&mut self, data: Bytes) -> Result<(), ServerError> {
+        match self.queue.push(data) {
+            PushResult::Queued => {
+                if self.overflowing {
+                    tracing::info!("Connection {} caught up", self.address);
+                    self.overflowing = false;
+                }
+                Ok(())
+            },
+            PushResult::Dropped => {
+                if !self.overflowing {
+                    tracing::warn!("Connection {} is not keeping up with the data, dropping data ({:?})", self.address, self.queue.policy);
+                    self.overflowing = true;
+                }
+                Ok(())
+            },
+            PushResult::Closed => {
+                //The writer may be stuck on a stalled client, stopping it closes the stream
+                self.writer.abort();
+                Err(ServerError::ConnectionClosed(self.address.to_string()))
+            }
+        }
+    }
+
+    /**
+        Close the connection, telling the client why with a Rejected frame. Anything still queued for the
+        client is discarded. Returns immediately; the frame is written in the background.
+     */
+    pub fn reject(self, reason: &str) {
+        self.queue.finish_with(convert_messages_to_bytes(vec![Message::rejection(reason)]));
+        tokio::spawn(self.finish(Instant::now() + REJECT_TIMEOUT));
+    }
+
+    ///Stop the connection, giving the client until the deadline to take the data already queued
+    pub async fn finish(mut self, deadline: Instant) {
+        self.queue.finish();
+        if tokio::time::timeout_at(deadline, &mut self.writer).await.is_err() {
+            tracing::warn!("Connection {} did not take all of its data before shutdown", self.address);
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.writer.abort();
+        self.reader.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn options(queue_size: usize, overflow_policy: OverflowPolicy) -> ConnectionOptions {
+        ConnectionOptions { queue_size, overflow_policy, heartbeat_timeout: None }
+    }
+
+    fn contents(queue: &OutboundQueue) -> Vec<u8> {
+        queue.state.lock().unwrap().data.iter().map(|(data, _)| data[0]).collect()
+    }
+
+    //A connected pair of (server side, client side) streams
+    async fn stream_pair() -> (TcpStream, SocketAddr, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (stream, address) = listener.accept().await.unwrap();
+        (stream, address, client)
+    }
+
+    #[test]
+    fn applies_overflow_policy() {
+        let expected = [
+            (OverflowPolicy::DropOldest, vec![PushResult::Queued, PushResult::Queued, PushResult::Dropped, PushResult::Dropped], vec![2, 3]),
+            (OverflowPolicy::DropNewest, vec![PushResult::Queued, PushResult::Queued, PushResult::Dropped, PushResult::Dropped], vec![0, 1]),
+            (OverflowPolicy::Disconnect, vec![PushResult::Queued, PushResult::Queued, PushResult::Closed, PushResult::Closed], vec![])
+        ];
+        for (policy, results, kept) in expected {
+            let queue = OutboundQueue::new(&options(2, policy));
+            let pushed: Vec<PushResult> = (0..4u8).map(|i| queue.push(Bytes::from(vec![i]))).collect();
+            assert_eq!(pushed, results, "{:?}", policy);
+            assert_eq!(contents(&queue), kept, "{:?}", policy);
+            assert_eq!(queue.is_closed(), policy == OverflowPolicy::Disconnect);
+        }
+    }
+
+    #[test]
+    fn keeps_control_frames_when_full() {
+        let hits = |timestamp| convert_messages_to_bytes(vec![Message { num_hits: timestamp, ..Default::default() }]);
+        let start = convert_messages_to_bytes(vec![Message { kind: MessageKind::RunStart, ..Default::default() }]);
+        let stop = convert_messages_to_bytes(vec![Message { kind: MessageKind::RunStop, ..Default::default() }]);
+        for policy in [OverflowPolicy::DropOldest, OverflowPolicy::DropNewest] {
+            //The RunStart is at the head of the full queue, so the oldest hits go instead of it
+            let queue = OutboundQueue::new(&options(2, policy));
+            assert_eq!(queue.push(start.clone()), PushResult::Queued);
+            assert_eq!(queue.push(hits(1)), PushResult::Queued);
+            assert_eq!(queue.push(hits(2)), PushResult::Dropped);
+            assert_eq!(queue.push(stop.clone()), PushResult::Dropped);
+            let kept: Vec<Bytes> = queue.state.lock().unwrap().data.iter().map(|(data, _)| data.clone()).collect();
+            assert_eq!(kept, vec![start.clone(), stop.clone()], "{:?}", policy);
+
+            //With only control frames queued, more are queued over capacity and data is dropped
+            assert_eq!(queue.push(stop.clone()), PushResult::Queued);
+            assert_eq!(queue.push(hits(3)), PushResult::Dropped);
+            assert_eq!(queue.len(), 3);
+        }
+    }
+
+    #[test]
+    fn bounds_heartbeats_and_control_frames() {
+        let heartbeat = |sequence| convert_messages_to_bytes(vec![Message { kind: MessageKind::Heartbeat, num_hits: sequence, ..Default::default() }]);
+        let stop = convert_messages_to_bytes(vec![Message { kind: MessageKind::RunStop, ..Default::default() }]);
+        for policy in [OverflowPolicy::DropOldest, OverflowPolicy::DropNewest] {
+            //Only the latest heartbeat is kept for a client which is not reading
+            let queue = OutboundQueue::new(&options(2, policy));
+            for sequence in 0..10 {
+                assert_ne!(queue.push(heartbeat(sequence)), PushResult::Closed);
+            }
+            let kept: Vec<Bytes> = queue.state.lock().unwrap().data.iter().map(|(data, _)| data.clone()).collect();
+            assert_eq!(kept, vec![heartbeat(9)], "{:?}", policy);
+
+            //A heartbeat makes way for control frames, which then go over capacity until the client is closed
+            for _ in 0..(2 + MAX_CONTROL_OVERFLOW) {
+                assert_ne!(queue.push(stop.clone()), PushResult::Closed, "{:?}", policy);
+            }
+            assert_eq!(queue.len(), 2 + MAX_CONTROL_OVERFLOW);
+            assert_eq!(queue.push(stop.clone()), PushResult::Closed, "{:?}", policy);
+            assert!(queue.is_closed());
+        }
+    }
+
+    #[tokio::test]
+    async fn slow_client_does_not_block_others() {
+        let (fast_stream, fast_address, mut fast_client) = stream_pair().await;
+        let (slow_stream, slow_address, _slow_client) = stream_pair().await;
+        let (stalled_stream, stalled_address, _stalled_client) = stream_pair().await;
+        let mut fast = Connection::new(fast_stream, fast_address, &options(256, OverflowPolicy::Disconnect), Arc::default());
+        let mut slow = Connection::new(slow_stream, slow_address, &options(4, OverflowPolicy::DropOldest), Arc::default());
+        let mut stalled = Connection::new(stalled_stream, stalled_address, &options(4, OverflowPolicy::Disconnect), Arc::default());
+
+        //Much more than the socket buffers can hold, so the clients which never read fall behind
+        let n_chunks = 200;
+        let chunk = Bytes::from(vec![7u8; 1 << 16]);
+        let reader = tokio::spawn(async move {
+            let mut received = vec![];
+            while received.len() < n_chunks * chunk.len() {
+                let mut buffer = [0; 1 << 16];
+                let n = fast_client.read(&mut buffer).await.unwrap();
+                assert!(n > 0);
+                received.extend_from_slice(&buffer[..n]);
+            }
+            received
+        });
+
+        let mut stalled_result = Ok(());
+        for _ in 0..n_chunks {
+            fast.send(&Bytes::from(vec![7u8; 1 << 16])).unwrap();
+            slow.send(&Bytes::from(vec![7u8; 1 << 16])).unwrap();
+            if stalled_result.is_ok() {
+                stalled_result = stalled.send(&Bytes::from(vec![7u8; 1 << 16]));
+            }
+        }
+
+        let received = tokio::time::timeout(Duration::from_secs(5), reader).await.unwrap().unwrap();
+        assert!(received.iter().all(|b| *b == 7));
+        assert!(fast.is_open());
+        assert!(slow.is_open());
+        assert!(slow.queued() <= 4);
+        assert!(matches!(stalled_result, Err(ServerError::ConnectionClosed(_))));
+        assert!(!stalled.is_open());
+    }
+
+    #[tokio::test]
+    async fn closes_clients_sending_oversized_frames() {
+        let (stream, address, mut client) = stream_pair().await;
+        let connection = Connection::new(stream, address, &ConnectionOptions::default(), Arc::default());
+
+        //Only the header is sent; the declared size alone gets the connection closed
+        let oversized = Message { kind: MessageKind::Subscribe, data: vec![0; 2 * MAX_COMMAND_SIZE], ..Default::default() };
+        let frame = convert_messages_to_bytes(vec![oversized]);
+        client.write_all(&frame[..100]).await.unwrap();
+
+        let mut received = vec![];
+        tokio::time::timeout(Duration::from_secs(2), client.read_to_end(&mut received)).await.unwrap().unwrap();
+        assert!(received.is_empty());
+        assert!(!connection.is_open());
+    }
+
+    #[tokio::test]
+    async fn finish_writes_queued_data() {
+        let (stream, address, mut client) = stream_pair().await;
+        let mut connection = Connection::new(stream, address, &ConnectionOptions::default(), Arc::default());
+        for i in 0..10u8 {
+            connection.send(&Bytes::from(vec![i; 100])).unwrap();
+        }
+        connection.finish(Instant::now() + Duration::from_secs(1)).await;
+
+        let mut received = vec![];
+        client.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received.len(), 1000);
+        assert_eq!(received[999], 9);
+    }
+}
@@ -0,0 +1,183 @@
+use serde::{Deserialize, Serialize};
+
+use crate::file::{ChannelId, CompassDataType, CompassHit};
+
+/**
+    Event building settings, read from the config.
+    coincidence_window is in timestamp units (ps) and is measured from the first hit of the event.
+    If trigger_channels is not empty, an event must contain a hit from at least one of them.
+    An event must have at least min_multiplicity hits, and at most max_multiplicity hits if given.
+ */
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct EventBuilderConfig {
+    pub coincidence_window: u64,
+    #[serde(default)]
+    pub trigger_channels: Vec<ChannelId>,
+    #[serde(default = "default_min_multiplicity")]
+    pub min_multiplicity: usize,
+    #[serde(default)]
+    pub max_multiplicity: Option<usize>
+}
+
+fn default_min_multiplicity() -> usize {
+    1
+}
+
+/**
+    A built event; a group of hits which fell within the coincidence window, in time order
+ */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CompassEvent {
+    pub hits: Vec<CompassHit>
+}
+
+impl CompassEvent {
+
+    ///Encode the event as the number of hits (u32, little endian) followed by the hits in the CoMPASS layout
+    pub fn encode(&self, data_type: CompassDataType) -> Vec<u8> {
+        let mut bytes = vec![];
+        bytes.extend_from_slice(&(self.hits.len() as u32).to_le_bytes());
+        self.hits.iter().for_each(|hit| bytes.append(&mut hit.encode(data_type)));
+        bytes
+    }
+
+    ///Decode all of the complete events in a buffer (i.e. the data of an Events Message)
+    pub fn decode_all(data_type: CompassDataType, buffer: &[u8]) -> Vec<CompassEvent> {
+        let mut events = vec![];
+        let mut position: usize = 0;
+        while position + 4 <= buffer.len() {
+            let n_hits = u32::from_le_bytes(buffer[position..(position + 4)].try_into().unwrap()) as usize;
+            position += 4;
+            let mut event = CompassEvent { hits: Vec::with_capacity(n_hits) };
+            for _ in 0..n_hits {
+                let (size, hit) = match (data_type.record_size(&buffer[position..]), CompassHit::decode(data_type, &buffer[position..])) {
+                    (Some(size), Some(hit)) => (size, hit),
+                    _ => return events
+                };
+                event.hits.push(hit);
+                position += size;
+            }
+            events.push(event);
+        }
+        events
+    }
+}
+
+/*
+    EventBuilder groups a time ordered stream of hits into events. Hits must be given in time order
+    (see HitMerger). Events which do not satisfy the trigger and multiplicity conditions are dropped.
+ */
+#[derive(Debug)]
+pub struct EventBuilder {
+    config: EventBuilderConfig,
+    current: Vec<CompassHit>
+}
+
+impl EventBuilder {
+
+    pub fn new(config: &EventBuilderConfig) -> Self {
+        EventBuilder { config: config.clone(), current: vec![] }
+    }
+
+    //Add a hit, returning the previous event if this hit closed it
+    pub fn push(&mut self, hit: CompassHit) -> Option<CompassEvent> {
+        let mut built = None;
+        if let Some(first) = self.current.first() {
+            if hit.timestamp.saturating_sub(first.timestamp) > self.config.coincidence_window {
+                built = self.close_event();
+            }
+        }
+        self.current.push(hit);
+        built
+    }
+
+    //Add many hits, returning all events which were closed
+    pub fn push_all(&mut self, hits: Vec<CompassHit>) -> Vec<CompassEvent> {
+        hits.into_iter().filter_map(|hit| self.push(hit)).collect()
+    }
+
+    //Close the event in progress. Used when no more hits are expected (i.e. end of run).
+    pub fn flush(&mut self) -> Option<CompassEvent> {
+        self.close_event()
+    }
+
+    fn close_event(&mut self) -> Option<CompassEvent> {
+        let event = CompassEvent { hits: std::mem::take(&mut self.current) };
+        if self.is_accepted(&event) {
+            Some(event)
+        } else {
+            None
+        }
+    }
+
+    fn is_accepted(&self, event: &CompassEvent) -> bool {
+        let multiplicity = event.hits.len();
+        if multiplicity == 0 || multiplicity < self.config.min_multiplicity {
+            return false;
+        }
+        if let Some(max) = self.config.max_multiplicity {
+            if multiplicity > max {
+                return false;
+            }
+        }
+        self.config.trigger_channels.is_empty() || event.hits.iter().any(|hit| {
+            self.config.trigger_channels.contains(&hit.channel_id())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hit(channel: u16, timestamp: u64) -> CompassHit {
+        CompassHit { channel, timestamp, ..Default::default() }
+    }
+
+    fn config(window: u64) -> EventBuilderConfig {
+        EventBuilderConfig { coincidence_window: window, trigger_channels: vec![], min_multiplicity: 1, max_multiplicity: None }
+    }
+
+    fn channels(event: &CompassEvent) -> Vec<u16> {
+        event.hits.iter().map(|h| h.channel).collect()
+    }
+
+    #[test]
+    fn groups_hits_in_window() {
+        let mut builder = EventBuilder::new(&config(100));
+        let events = builder.push_all(vec![hit(0, 0), hit(1, 50), hit(2, 100), hit(3, 101), hit(4, 150)]);
+        assert_eq!(events.len(), 1);
+        assert_eq!(channels(&events[0]), vec![0, 1, 2]);
+
+        assert_eq!(channels(&builder.push(hit(5, 250)).unwrap()), vec![3, 4]);
+        assert_eq!(channels(&builder.push(hit(6, 1000)).unwrap()), vec![5]);
+        assert_eq!(channels(&builder.flush().unwrap()), vec![6]);
+        assert!(builder.flush().is_none());
+    }
+
+    #[test]
+    fn applies_multiplicity_conditions() {
+        let mut cfg = config(10);
+        cfg.min_multiplicity = 2;
+        cfg.max_multiplicity = Some(3);
+        let mut builder = EventBuilder::new(&cfg);
+        let events = builder.push_all(vec![
+            hit(0, 0), //Too few
+            hit(0, 100), hit(1, 105), //Accepted
+            hit(0, 200), hit(1, 201), hit(2, 202), hit(3, 203), //Too many
+            hit(0, 300)
+        ]);
+        assert_eq!(events.len(), 1);
+        assert_eq!(channels(&events[0]), vec![0, 1]);
+    }
+
+    #[test]
+    fn requires_trigger_channel() {
+        let mut cfg = config(10);
+        cfg.trigger_channels = vec![ChannelId { board: 0, channel: 7 }];
+        let mut builder = EventBuilder::new(&cfg);
+        let events = builder.push_all(vec![hit(1, 0), hit(2, 5), hit(3, 100), hit(7, 102), hit(0, 500)]);
+        assert_eq!(events.len(), 1);
+        assert_eq!(channels(&events[0]), vec![3, 7]);
+    }
+}
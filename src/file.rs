@@ -1,22 +1,21 @@
 use std::fmt::Display;
 use std::fs::File;
 use std::path::{Path, PathBuf};
-use std::io::{BufReader, Read};
+use std::io::{BufReader, Read, Seek, SeekFrom};
 use bitflags::bitflags;
+use serde::{Deserialize, Serialize};
 
-use crate::message::Message;
+use crate::message::{Message, MessageSource};
 
 #[derive(Debug)]
 pub enum CompassFileError {
-    IOError(PathBuf, std::io::Error),
-    WavesError(PathBuf)
+    IOError(PathBuf, std::io::Error)
 }
 
 impl Display for CompassFileError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Self::IOError(name, error) => write!(f, "File {} ran into an error: {}", name.display(), error),
-            Self::WavesError(name) => write!(f, "File {} was detected to be in waves mode!", name.display())
+            Self::IOError(name, error) => write!(f, "File {} ran into an error: {}", name.display(), error)
         }
     }
 }
@@ -25,8 +24,17 @@ impl std::error::Error for CompassFileError {
 
 }
 
+//Size of the file header (the CompassDataType word)
+const HEADER_SIZE: u64 = 2;
+
+//Size of the waveform code and number of samples words which precede the samples in waves mode
+const WAVES_HEADER_SIZE: usize = 5;
+
+//Most bytes read at a time when reading hit by hit
+const READ_CHUNK_SIZE: u64 = 1 << 16;
+
 bitflags! {
-    #[derive(Debug, Clone)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     pub struct CompassDataType: u16 {
         const ENERGY = 0x0001;
         const ENERGY_SHORT = 0x0004;
@@ -37,19 +45,209 @@ bitflags! {
     }
 }
 
+impl CompassDataType {
+
+    ///Size of the fixed portion of a record with this data type. Waves records are followed by their samples.
+    pub fn hit_size(&self) -> usize {
+        let mut datasize: usize = 16; //minimum 16 bytes for board, channel, timestamp, flags
+        if self.contains(CompassDataType::ENERGY) {
+            datasize += 2;
+        }
+        if self.contains(CompassDataType::ENERGY_SHORT) {
+            datasize += 2;
+        }
+        if self.contains(CompassDataType::ENERGY_CALIBRATED) {
+            datasize += 8;
+        }
+        if self.contains(CompassDataType::WAVES) {
+            datasize += WAVES_HEADER_SIZE;
+        }
+        datasize
+    }
+
+    /**
+        Size of the record at the start of the buffer, if the buffer holds enough of the record
+        to know it. Fixed-size records are always hit_size; waves records need the sample count.
+     */
+    pub fn record_size(&self, buffer: &[u8]) -> Option<usize> {
+        let hit_size = self.hit_size();
+        if buffer.len() < hit_size {
+            return None;
+        }
+        if !self.contains(CompassDataType::WAVES) {
+            return Some(hit_size);
+        }
+        let mut n_samples: [u8; 4] = [0; 4];
+        n_samples.copy_from_slice(&buffer[(hit_size - 4)..hit_size]);
+        Some(hit_size + 2 * u32::from_le_bytes(n_samples) as usize)
+    }
+}
+
+/**
+    Identifies a single digitizer channel
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize)]
+pub struct ChannelId {
+    pub board: u16,
+    pub channel: u16
+}
+
+/**
+    A single decoded CoMPASS hit. Fields which are not present in the file (as determined by
+    the CompassDataType of the header) are left as zero.
+    The on-disk layout is board, channel, timestamp, energy, energy calibrated, energy short, flags
+    (all little endian). In waves mode the record is followed by a waveform code (u8), the number of
+    samples (u32), and the samples themselves (u16 each), making the record variable length.
+ */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CompassHit {
+    pub board: u16,
+    pub channel: u16,
+    pub timestamp: u64, //In picoseconds
+    pub energy: u16,
+    pub energy_short: u16,
+    pub energy_calibrated: f64,
+    pub flags: u32,
+    pub waveform_code: u8,
+    pub samples: Vec<u16> //Empty unless the file is in waves mode
+}
+
+impl CompassHit {
+
+    pub fn channel_id(&self) -> ChannelId {
+        ChannelId { board: self.board, channel: self.channel }
+    }
+
+    /**
+        Decode the record of the given data type at the start of the buffer.
+        None if the buffer does not hold the entire record, including any waveform samples.
+     */
+    pub fn decode(data_type: CompassDataType, buffer: &[u8]) -> Option<CompassHit> {
+        match data_type.record_size(buffer) {
+            Some(size) if size <= buffer.len() => {},
+            _ => return None
+        }
+        let mut hit = CompassHit::default();
+        let mut cursor = ByteCursor::new(buffer);
+
+        hit.board = cursor.read_u16();
+        hit.channel = cursor.read_u16();
+        hit.timestamp = cursor.read_u64();
+        if data_type.contains(CompassDataType::ENERGY) {
+            hit.energy = cursor.read_u16();
+        }
+        if data_type.contains(CompassDataType::ENERGY_CALIBRATED) {
+            hit.energy_calibrated = f64::from_bits(cursor.read_u64());
+        }
+        if data_type.contains(CompassDataType::ENERGY_SHORT) {
+            hit.energy_short = cursor.read_u16();
+        }
+        hit.flags = cursor.read_u32();
+        if data_type.contains(CompassDataType::WAVES) {
+            hit.waveform_code = cursor.read_u8();
+            let n_samples = cursor.read_u32() as usize;
+            hit.samples = (0..n_samples).map(|_| cursor.read_u16()).collect();
+        }
+
+        Some(hit)
+    }
+
+    ///Decode all of the complete records in a buffer (i.e. the data of a Message)
+    pub fn decode_all(data_type: CompassDataType, buffer: &[u8]) -> Vec<CompassHit> {
+        let mut hits = vec![];
+        let mut position: usize = 0;
+        while let Some(size) = data_type.record_size(&buffer[position..]) {
+            match CompassHit::decode(data_type, &buffer[position..]) {
+                Some(hit) => hits.push(hit),
+                None => break
+            }
+            position += size;
+        }
+        hits
+    }
+
+    ///Encode the hit in the CoMPASS binary layout for the given data type
+    pub fn encode(&self, data_type: CompassDataType) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(data_type.hit_size() + 2 * self.samples.len());
+        bytes.extend_from_slice(&self.board.to_le_bytes());
+        bytes.extend_from_slice(&self.channel.to_le_bytes());
+        bytes.extend_from_slice(&self.timestamp.to_le_bytes());
+        if data_type.contains(CompassDataType::ENERGY) {
+            bytes.extend_from_slice(&self.energy.to_le_bytes());
+        }
+        if data_type.contains(CompassDataType::ENERGY_CALIBRATED) {
+            bytes.extend_from_slice(&self.energy_calibrated.to_le_bytes());
+        }
+        if data_type.contains(CompassDataType::ENERGY_SHORT) {
+            bytes.extend_from_slice(&self.energy_short.to_le_bytes());
+        }
+        bytes.extend_from_slice(&self.flags.to_le_bytes());
+        if data_type.contains(CompassDataType::WAVES) {
+            bytes.push(self.waveform_code);
+            bytes.extend_from_slice(&(self.samples.len() as u32).to_le_bytes());
+            self.samples.iter().for_each(|s| bytes.extend_from_slice(&s.to_le_bytes()));
+        }
+        bytes
+    }
+}
+
+//Minimal little endian reader over a byte slice. Callers are responsible for bounds.
+struct ByteCursor<'a> {
+    buffer: &'a [u8],
+    position: usize
+}
+
+impl<'a> ByteCursor<'a> {
+    fn new(buffer: &'a [u8]) -> Self {
+        ByteCursor { buffer, position: 0 }
+    }
+
+    fn take<const N: usize>(&mut self) -> [u8; N] {
+        let mut bytes = [0; N];
+        bytes.copy_from_slice(&self.buffer[self.position..(self.position + N)]);
+        self.position += N;
+        bytes
+    }
+
+    fn read_u8(&mut self) -> u8 {
+        u8::from_le_bytes(self.take())
+    }
+
+    fn read_u16(&mut self) -> u16 {
+        u16::from_le_bytes(self.take())
+    }
+
+    fn read_u32(&mut self) -> u32 {
+        u32::from_le_bytes(self.take())
+    }
+
+    fn read_u64(&mut self) -> u64 {
+        u64::from_le_bytes(self.take())
+    }
+}
+
 /*
     Simple representation of a CoMPASS binary data file. 
+    hit_size is the size of the fixed portion of a record; in waves mode each record is
+    additionally followed by its samples (2 bytes per sample). Bytes which have been read from
+    disk but do not yet form a complete record are kept in pending, and offset is the number of bytes
+    of the file which have been read so far (including the header). Each file holds the data of a
+    single channel, which is learned from the first record.
  */
 
 #[derive(Debug)]
 pub struct CompassFile {
     filepath: PathBuf,
     handle: BufReader<File>,
-    data_type: u16,
-    hit_size: usize
+    data_type: CompassDataType,
+    hit_size: usize,
+    pending: Vec<u8>,
+    offset: u64,
+    channel: Option<ChannelId>
 }
 
 impl CompassFile {
+    ///Open a CoMPASS binary file and read its header. Data is then read as it is appended to the file.
     pub fn new(path: &Path) -> Result<CompassFile, CompassFileError> {
         let mut file: File = match File::open(path) {
             Ok(f) => f,
@@ -64,45 +262,410 @@ impl CompassFile {
         };
         let header_word = u16::from_le_bytes(header);
 
-        let mut datatype = CompassDataType::NONE;
-        let mut datasize: usize = 16; //minimum 16 bytes for board, channel, timestamp, flags
+        let datatype = CompassDataType::from_bits_truncate(header_word);
 
-        //determine the header type and data size.
-        if header_word & CompassDataType::ENERGY.bits() != 0 {
-            datatype |= CompassDataType::ENERGY;
-            datasize += 2;
+        Ok(CompassFile {
+            filepath: path.to_path_buf(),
+            handle: BufReader::new(file),
+            data_type: datatype,
+            hit_size: datatype.hit_size(),
+            pending: vec![],
+            offset: HEADER_SIZE,
+            channel: None
+        })
+
+    }
+
+    ///Path of the file
+    pub fn path(&self) -> &Path {
+        &self.filepath
+    }
+
+    ///Data type from the file header, which determines the layout of its records
+    pub fn data_type(&self) -> CompassDataType {
+        self.data_type
+    }
+
+    ///Number of bytes of the file read so far
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /**
+        Check if the file has grown past what has been read, without reading it. This is cheap enough
+        to call for every file whenever anything happens, so that no event is needed for a particular file.
+     */
+    pub fn has_new_data(&self) -> Result<bool, CompassFileError> {
+        match std::fs::metadata(&self.filepath) {
+            Ok(meta) => {
+                if meta.len() < self.offset {
+                    tracing::warn!("File {} is shorter than what has already been read from it", self.filepath.display());
+                }
+                Ok(meta.len() > self.offset)
+            },
+            Err(e) => Err(CompassFileError::IOError(self.filepath.clone(), e))
         }
-        if header_word & CompassDataType::ENERGY_SHORT.bits() != 0 {
-            datatype |= CompassDataType::ENERGY_SHORT;
-            datasize += 2;
+    }
+
+    ///Size of a record in this file, not including the samples of waves records
+    pub fn hit_size(&self) -> usize {
+        self.hit_size
+    }
+
+    ///Waves records vary in length, so Messages from this file have a hit_size of 0
+    pub fn is_waves(&self) -> bool {
+        self.data_type.contains(CompassDataType::WAVES)
+    }
+
+    ///Identity of this file for Messages
+    pub fn source(&self) -> MessageSource {
+        let file = match self.filepath.file_name() {
+            Some(name) => name.to_string_lossy().to_string(),
+            None => String::new()
+        };
+        MessageSource { channel: self.channel, file, ..Default::default() }
+    }
+
+    fn record_size(&self, buffer: &[u8]) -> Option<usize> {
+        self.data_type.record_size(buffer)
+    }
+
+    //Length in bytes and number of the complete records at the start of the buffer
+    fn complete_records(&self, buffer: &[u8]) -> (usize, u64) {
+        let mut position: usize = 0;
+        let mut count: u64 = 0;
+        while let Some(size) = self.record_size(&buffer[position..]) {
+            if position + size > buffer.len() {
+                break;
+            }
+            position += size;
+            count += 1;
         }
-        if header_word & CompassDataType::ENERGY_CALIBRATED.bits() != 0 {
-            datatype |= CompassDataType::ENERGY_CALIBRATED;
-            datasize += 8;
+        (position, count)
+    }
+
+    //Pull everything currently written to the file into the pending buffer
+    fn fill_buffer(&mut self) -> Result<(), CompassFileError> {
+        match self.handle.read_to_end(&mut self.pending) {
+            Ok(size) => {
+                self.offset += size as u64;
+                Ok(())
+            },
+            Err(e) => Err(CompassFileError::IOError(self.filepath.clone(), e))
         }
-        if header_word & CompassDataType::WAVES.bits() != 0 {
-            return Err(CompassFileError::WavesError(path.to_path_buf()));
+    }
+
+    /**
+        Read data from the file and make a Message. Only whole records are emitted; CoMPASS flushes
+        in arbitrary chunks, so any trailing partial record is held back until the rest of it is written.
+        Waves mode records are variable length, and are flagged by a hit_size of 0 in the Message.
+     */
+    pub fn read_data(&mut self) -> Result<Message, CompassFileError> {
+        let mut message = Message { data_type: self.data_type.bits(), ..Default::default() };
+        if !self.is_waves() {
+            message.hit_size = self.hit_size as u64;
         }
-        
+        self.fill_buffer()?;
 
-        return Ok(CompassFile {
-            filepath: path.to_path_buf(),
-            handle: BufReader::new(file),
-            data_type: header_word,
-            hit_size: datasize,
+        let (length, count) = self.complete_records(&self.pending);
+        message.data = self.pending.drain(..length).collect();
+        message.num_hits = count;
+        if self.channel.is_none() && count > 0 {
+            self.channel = CompassHit::decode(self.data_type, &message.data).map(|hit| hit.channel_id());
+        }
+        message.source = self.source();
+        Ok(message)
+    }
+
+    /**
+        Skip past all of the complete records currently in the file. Fixed size records can be skipped
+        with a seek; waves records have to be walked.
+     */
+    pub fn skip_to_end(&mut self) -> Result<(), CompassFileError> {
+        if self.is_waves() {
+            return self.read_data().map(|_| ());
+        }
+
+        let result = self.handle.seek(SeekFrom::End(0)).and_then(|end| {
+            let records = end.saturating_sub(HEADER_SIZE) / self.hit_size as u64;
+            self.pending.clear();
+            self.handle.seek(SeekFrom::Start(HEADER_SIZE + records * self.hit_size as u64))
         });
+        match result {
+            Ok(position) => {
+                self.offset = position;
+                Ok(())
+            },
+            Err(e) => Err(CompassFileError::IOError(self.filepath.clone(), e))
+        }
+    }
 
+    //Pull at most READ_CHUNK_SIZE more bytes into the pending buffer, returning how many were read
+    fn fill_buffer_chunk(&mut self) -> Result<usize, CompassFileError> {
+        match (&mut self.handle).take(READ_CHUNK_SIZE).read_to_end(&mut self.pending) {
+            Ok(size) => {
+                self.offset += size as u64;
+                Ok(size)
+            },
+            Err(e) => Err(CompassFileError::IOError(self.filepath.clone(), e))
+        }
     }
 
-    //Read data from the file and make a Message
-    pub fn read_data(&mut self) -> Result<Message, CompassFileError> {
-        let mut message = Message::default();
-        message.data_type = self.data_type;
-        message.hit_size = self.hit_size as u64;
-        match self.handle.read_to_end(&mut message.data) {
-            Ok(size) => message.size += size as u64,
-            Err(e) => return Err(CompassFileError::IOError(self.filepath.clone(), e))
+    /**
+        Read the next hit from the file. Returns Ok(None) when there is no complete hit available.
+        The file is read in chunks, so that walking a large file hit by hit doesn't hold all of it in memory.
+     */
+    pub fn read_hit(&mut self) -> Result<Option<CompassHit>, CompassFileError> {
+        loop {
+            let needs_data = match self.record_size(&self.pending) {
+                Some(size) => size > self.pending.len(),
+                None => true
+            };
+            if !needs_data || self.fill_buffer_chunk()? == 0 {
+                break;
+            }
+        }
+
+        let size = match self.record_size(&self.pending) {
+            Some(size) => size,
+            None => return Ok(None)
         };
-        return Ok(message);
+        match CompassHit::decode(self.data_type, &self.pending) {
+            Some(hit) => {
+                self.pending.drain(..size);
+                self.channel.get_or_insert(hit.channel_id());
+                Ok(Some(hit))
+            }
+            None => Ok(None)
+        }
+    }
+
+    ///Iterate over all hits currently available in the file
+    pub fn hits(&mut self) -> CompassHits<'_> {
+        CompassHits { file: self }
+    }
+}
+
+/**
+    Iterator over the decoded hits of a CompassFile. Iteration stops at the current end of the file;
+    calling CompassFile::hits again later will pick up any data written in the meantime.
+ */
+#[derive(Debug)]
+pub struct CompassHits<'a> {
+    file: &'a mut CompassFile
+}
+
+impl Iterator for CompassHits<'_> {
+    type Item = Result<CompassHit, CompassFileError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.file.read_hit().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn write_file(dir: &Path, data_type: CompassDataType, hits: &[CompassHit]) -> PathBuf {
+        let path = dir.join("DataR_CH0@V1730_123_run_1.BIN");
+        let mut file = File::create(&path).unwrap();
+        file.write_all(&data_type.bits().to_le_bytes()).unwrap();
+        for hit in hits {
+            file.write_all(&hit.encode(data_type)).unwrap();
+        }
+        path
     }
-}
\ No newline at end of file
+
+    fn sample_hits() -> Vec<CompassHit> {
+        vec![
+            CompassHit { board: 0, channel: 1, timestamp: 1000, energy: 512, energy_short: 100, energy_calibrated: 1.5, flags: 0x4000, ..Default::default() },
+            CompassHit { board: 2, channel: 15, timestamp: 2500, energy: 4095, energy_short: 7, energy_calibrated: -3.25, flags: 0, ..Default::default() },
+        ]
+    }
+
+    #[test]
+    fn decodes_all_fields() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_type = CompassDataType::ENERGY | CompassDataType::ENERGY_SHORT | CompassDataType::ENERGY_CALIBRATED;
+        let path = write_file(dir.path(), data_type, &sample_hits());
+
+        let mut file = CompassFile::new(&path).unwrap();
+        assert_eq!(file.hit_size(), 28);
+        let hits: Vec<CompassHit> = file.hits().map(|h| h.unwrap()).collect();
+        assert_eq!(hits, sample_hits());
+    }
+
+    #[test]
+    fn decodes_partial_data_type() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_type = CompassDataType::ENERGY;
+        let path = write_file(dir.path(), data_type, &sample_hits());
+
+        let mut file = CompassFile::new(&path).unwrap();
+        assert_eq!(file.hit_size(), 18);
+        let hits: Vec<CompassHit> = file.hits().map(|h| h.unwrap()).collect();
+        assert_eq!(hits.len(), 2);
+        assert_eq!(hits[1].energy, 4095);
+        assert_eq!(hits[1].energy_short, 0);
+        assert_eq!(hits[1].energy_calibrated, 0.0);
+        assert_eq!(hits[1].flags, 0);
+    }
+
+    #[test]
+    fn decodes_waves() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_type = CompassDataType::ENERGY | CompassDataType::WAVES;
+        let mut hits = sample_hits();
+        hits.iter_mut().for_each(|h| { h.energy_short = 0; h.energy_calibrated = 0.0; h.waveform_code = 1; });
+        hits[0].samples = vec![10, 20, 30];
+        hits[1].samples = (0..100).collect();
+        let path = write_file(dir.path(), data_type, &hits);
+
+        let mut file = CompassFile::new(&path).unwrap();
+        assert!(file.is_waves());
+        let decoded: Vec<CompassHit> = file.hits().map(|h| h.unwrap()).collect();
+        assert_eq!(decoded, hits);
+    }
+
+    #[test]
+    fn rejects_truncated_records() {
+        let hit = CompassHit { waveform_code: 1, samples: vec![1, 2, 3], ..sample_hits()[1].clone() };
+        for data_type in [CompassDataType::ENERGY, CompassDataType::ALL] {
+            let bytes = hit.encode(data_type);
+            for length in 0..bytes.len() {
+                assert_eq!(CompassHit::decode(data_type, &bytes[..length]), None, "{:?} {}", data_type, length);
+            }
+            assert_eq!(CompassHit::decode(data_type, &bytes).unwrap().timestamp, hit.timestamp);
+        }
+    }
+
+    #[test]
+    fn waves_message_is_variable_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_type = CompassDataType::ENERGY | CompassDataType::WAVES;
+        let mut hits = sample_hits();
+        hits[0].samples = vec![1; 4];
+        hits[1].samples = vec![2; 9];
+        let path = write_file(dir.path(), data_type, &hits);
+
+        let mut file = CompassFile::new(&path).unwrap();
+        let message = file.read_data().unwrap();
+        assert_eq!(message.hit_size, 0);
+        assert_eq!(message.num_hits, 2);
+        assert_eq!(message.source.channel, Some(ChannelId { board: 0, channel: 1 }));
+        assert_eq!(message.source.file, "DataR_CH0@V1730_123_run_1.BIN");
+        assert_eq!(message.data.len(), 2 * 23 + 2 * (4 + 9));
+    }
+
+    //Emulate CoMPASS flushing a file in chunks which do not line up with record boundaries
+    fn append_in_chunks(path: &Path, bytes: &[u8], chunk_size: usize, mut on_chunk: impl FnMut()) {
+        let mut file = std::fs::OpenOptions::new().append(true).open(path).unwrap();
+        for chunk in bytes.chunks(chunk_size) {
+            file.write_all(chunk).unwrap();
+            file.flush().unwrap();
+            on_chunk();
+        }
+    }
+
+    #[test]
+    fn read_data_only_emits_whole_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_type = CompassDataType::ENERGY | CompassDataType::ENERGY_SHORT;
+        let path = write_file(dir.path(), data_type, &[]);
+        let hits: Vec<CompassHit> = (0..20).map(|i| CompassHit { timestamp: i * 10, energy: i as u16, ..Default::default() }).collect();
+        let bytes: Vec<u8> = hits.iter().flat_map(|h| h.encode(data_type)).collect();
+
+        let mut file = CompassFile::new(&path).unwrap();
+        let mut received: Vec<u8> = vec![];
+        let mut total_hits: u64 = 0;
+        for chunk_size in [7, 13, 1, 31] {
+            append_in_chunks(&path, &bytes, chunk_size, || {
+                let message = file.read_data().unwrap();
+                assert_eq!(message.data.len() % file.hit_size(), 0);
+                assert_eq!(message.num_hits as usize, message.data.len() / file.hit_size());
+                total_hits += message.num_hits;
+                received.extend(message.data);
+            });
+        }
+        assert_eq!(total_hits, 80);
+        assert_eq!(received, bytes.repeat(4));
+    }
+
+    #[test]
+    fn read_data_only_emits_whole_waves_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_type = CompassDataType::ENERGY | CompassDataType::WAVES;
+        let path = write_file(dir.path(), data_type, &[]);
+        let hits: Vec<CompassHit> = (0..10).map(|i| CompassHit { timestamp: i, samples: vec![i as u16; i as usize * 3], ..Default::default() }).collect();
+        let bytes: Vec<u8> = hits.iter().flat_map(|h| h.encode(data_type)).collect();
+
+        let mut file = CompassFile::new(&path).unwrap();
+        let mut received: Vec<u8> = vec![];
+        append_in_chunks(&path, &bytes, 11, || {
+            let message = file.read_data().unwrap();
+            let (length, count) = file.complete_records(&message.data);
+            assert_eq!(length, message.data.len());
+            assert_eq!(count, message.num_hits);
+            received.extend(message.data);
+        });
+        assert_eq!(received, bytes);
+    }
+
+    #[test]
+    fn hits_resume_across_partial_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_type = CompassDataType::ENERGY | CompassDataType::ENERGY_CALIBRATED;
+        let path = write_file(dir.path(), data_type, &[]);
+        let hits: Vec<CompassHit> = (0..15).map(|i| CompassHit { channel: i as u16, timestamp: i * 3, energy_calibrated: i as f64 * 0.5, ..Default::default() }).collect();
+        let bytes: Vec<u8> = hits.iter().flat_map(|h| h.encode(data_type)).collect();
+
+        let mut file = CompassFile::new(&path).unwrap();
+        let mut decoded: Vec<CompassHit> = vec![];
+        append_in_chunks(&path, &bytes, 9, || {
+            decoded.extend(file.hits().map(|h| h.unwrap()));
+        });
+        assert_eq!(decoded, hits);
+    }
+
+    #[test]
+    fn skip_to_end_keeps_partial_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_type = CompassDataType::ENERGY;
+        let path = write_file(dir.path(), data_type, &sample_hits());
+        let mut file = CompassFile::new(&path).unwrap();
+
+        //Half of a third hit is already on disk when skipping
+        let third = CompassHit { timestamp: 9000, ..Default::default() }.encode(data_type);
+        append_in_chunks(&path, &third[..10], 10, || {});
+        file.skip_to_end().unwrap();
+        assert_eq!(file.read_data().unwrap().num_hits, 0);
+        append_in_chunks(&path, &third[10..], 10, || {});
+        let hits: Vec<CompassHit> = file.hits().map(|h| h.unwrap()).collect();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].timestamp, 9000);
+        assert!(file.hits().next().is_none());
+    }
+
+    #[test]
+    fn tracks_offset_of_appends() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_type = CompassDataType::ENERGY;
+        let path = write_file(dir.path(), data_type, &[]);
+        let mut file = CompassFile::new(&path).unwrap();
+        assert_eq!(file.offset(), 2);
+        assert!(!file.has_new_data().unwrap());
+
+        let bytes: Vec<u8> = sample_hits().iter().flat_map(|h| h.encode(data_type)).collect();
+        let mut expected_offset = 2;
+        append_in_chunks(&path, &bytes, 5, || {
+            expected_offset += 5.min(bytes.len() + 2 - expected_offset as usize) as u64;
+            assert!(file.has_new_data().unwrap());
+            file.read_data().unwrap();
+            assert_eq!(file.offset(), expected_offset);
+            assert!(!file.has_new_data().unwrap());
+        });
+        assert_eq!(file.offset(), bytes.len() as u64 + 2);
+    }
+}
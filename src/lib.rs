@@ -0,0 +1,189 @@
+//! Ritual streams data from a CAEN CoMPASS project over TCP as it is written.
+//!
+//! The binary is a thin wrapper around this library. The same parsing code can be used by clients:
+//! [`CompassFile`] reads CoMPASS binary data files, [`Message`] encodes and decodes the frames sent
+//! by the server, and [`Ritual`] (or [`run()`]) runs the whole pipeline in-process.
+//!
+//! Decoding the frames received from a ritual server:
+//!
+//! ```no_run
+//! use std::io::Read;
+//! use ritual::{CompassHit, CompassDataType, Message, MessageError, MessageKind};
+//!
+//! let mut stream = std::net::TcpStream::connect("127.0.0.1:52324").unwrap();
+//! let mut buffer = vec![];
+//! loop {
+//!     match Message::decode(&buffer) {
+//!         Ok((message, size)) => {
+//!             buffer.drain(..size);
+//!             if message.kind == MessageKind::Hits {
+//!                 let data_type = CompassDataType::from_bits_truncate(message.data_type);
+//!                 for hit in CompassHit::decode_all(data_type, &message.data) {
+//!                     println!("{:?}", hit);
+//!                 }
+//!             }
+//!         },
+//!         Err(MessageError::Incomplete(_)) => {
+//!             let mut chunk = [0; 4096];
+//!             let n = stream.read(&mut chunk).unwrap();
+//!             buffer.extend_from_slice(&chunk[..n]);
+//!         },
+//!         Err(e) => panic!("{}", e)
+//!     }
+//! }
+//! ```
+
+/// TCP server sending data to the connected clients
+pub mod server;
+/// Client connections of the server
+pub mod connection;
+/// Client commands and per-client filtering of the data
+pub mod subscription;
+/// Watching the project directory with Notify
+pub mod watcher;
+/// Following the runs of a CoMPASS project and sending their data
+pub mod project;
+/// Run directories and the data files of a run
+pub mod run;
+/// Reading CoMPASS binary data files
+pub mod file;
+/// The wire protocol: frames sent by the server
+pub mod message;
+/// Merging hits from several files in timestamp order
+pub mod merge;
+/// Building events from time ordered hits
+pub mod event;
+/// Configuration of ritual, read from a yaml file
+pub mod config;
+/// CoMPASS acquisition settings (settings.xml)
+pub mod settings;
+/// Replaying a finished run
+pub mod replay;
+/// Writing simulated CoMPASS data
+pub mod simulate;
+
+pub use config::Config;
+pub use connection::{Connection, ConnectionOptions};
+pub use file::{CompassDataType, CompassFile, CompassFileError, CompassHit, ChannelId};
+pub use message::{Heartbeat, Message, MessageError, MessageKind, MessageSource};
+pub use project::{Project, ProjectError};
+pub use run::{RunId, RunInfo};
+pub use server::{ServerError, ServerHandle, ServerOptions, run_server};
+pub use subscription::{ChannelSelector, Command, Streams};
+pub use watcher::ProjectWatcher;
+
+use bytes::Bytes;
+use std::future::Future;
+use std::net::SocketAddr;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+#[derive(Debug)]
+pub enum RitualError {
+    ServerError(ServerError),
+    ProjectError(ProjectError),
+    WatchError(notify::Error)
+}
+
+impl From<ServerError> for RitualError {
+    fn from(value: ServerError) -> Self {
+        RitualError::ServerError(value)
+    }
+}
+
+impl From<ProjectError> for RitualError {
+    fn from(value: ProjectError) -> Self {
+        RitualError::ProjectError(value)
+    }
+}
+
+impl From<notify::Error> for RitualError {
+    fn from(value: notify::Error) -> Self {
+        RitualError::WatchError(value)
+    }
+}
+
+impl std::fmt::Display for RitualError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ServerError(e) => write!(f, "Server initialization error: {}", e),
+            Self::ProjectError(e) => write!(f, "Project initialization error: {}", e),
+            Self::WatchError(e) => write!(f, "Notify error: {}", e)
+        }
+    }
+}
+
+impl std::error::Error for RitualError {
+
+}
+
+/**
+    A running instance of ritual: the server, the Project task, and the watcher feeding it.
+    Notify is a synchronous crate; its watcher runs on its own thread and bridges into the Project
+    through the blocking functionality of tokio channels, so it only has to be kept alive here.
+    The Project never changes the watch itself: the watcher may be blocked sending to the Project, so waiting on it
+    from the Project could deadlock. The whole project directory is watched instead, and the Project filters the events.
+ */
+#[derive(Debug)]
+pub struct Ritual {
+    server: ServerHandle,
+    project: JoinHandle<()>,
+    watcher: ProjectWatcher
+}
+
+impl Ritual {
+
+    ///Start the server, the Project, and the watcher of the project directory
+    pub async fn start(config: &Config) -> Result<Ritual, RitualError> {
+        //Data channels
+        let (data_sender, data_reciever) = tokio::sync::mpsc::channel::<Bytes>(10);
+        let (event_sender, event_reciever) = tokio::sync::mpsc::channel::<notify::event::Event>(5);
+
+        //Initialize the server, spawining server tasks
+        let server = run_server(&config.server_address, ServerOptions::new(config), data_reciever).await?;
+
+        let mut project = Project::new(config, event_reciever, data_sender)?;
+        let project = tokio::spawn(async move {
+            match project.handle_events().await {
+                Ok(_) => {},
+                Err(e) => tracing::error!("Project error: {}", e)
+            }
+        });
+
+        let poll_interval = std::time::Duration::from_millis(config.poll_interval_ms.max(1));
+        let mut watcher = ProjectWatcher::new(event_sender, config.watch_mode, poll_interval)?;
+        watcher.watch(&config.project_directory, notify::RecursiveMode::Recursive)?;
+
+        Ok(Ritual { server, project, watcher })
+    }
+
+    ///The address the server is listening on
+    pub fn server_address(&self) -> SocketAddr {
+        self.server.address()
+    }
+
+    ///Watch of the number of connected clients
+    pub fn connections(&self) -> watch::Receiver<usize> {
+        self.server.connections()
+    }
+
+    /**
+        Run until the shutdown future completes. Stopping the watcher closes the event channel, which ends the
+        Project once it has handled the remaining events; the server then sends out what is left and stops.
+     */
+    pub async fn run_until(self, shutdown: impl Future<Output = ()>) {
+        shutdown.await;
+        tracing::info!("Shutting down.");
+        drop(self.watcher);
+        if let Err(e) = self.project.await {
+            tracing::error!("Project task failed: {}", e);
+        }
+        self.server.shutdown().await;
+    }
+}
+
+///Run ritual with the given config until the shutdown future completes
+pub async fn run(config: Config, shutdown: impl Future<Output = ()>) -> Result<(), RitualError> {
+    Ritual::start(&config).await?.run_until(shutdown).await;
+    Ok(())
+}
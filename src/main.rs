@@ -1,19 +1,15 @@
-mod server;
-mod watcher;
-mod project;
-mod file;
-mod message;
-mod config;
-
 use bytes::Bytes;
-use server::run_server;
-use watcher::create_watcher;
-use project::Project;
-use config::{Config, read_config_file};
+use ritual::{ServerOptions, run_server};
+use ritual::Config;
+use ritual::config::read_config_file;
+use ritual::replay::{Replay, ReplaySpeed};
+use ritual::simulate::{SimulationConfig, Simulator, read_simulation_config_file};
 
 //Simple help statement
 fn print_help() {
     print!("Ritual is run as:\ncargo -r run -- <your_config>\nThe config file is a yaml file which contains the server address and project directory\n");
+    print!("To replay a finished run instead:\ncargo -r run -- replay <your_config> <run_directory> [speed]\nwhere speed is max (default), realtime, or a multiple of real time such as 2x\n");
+    print!("To write simulated CoMPASS data to a project directory:\ncargo -r run -- simulate <project_directory> [simulation_config]\n");
 }
 
 fn get_config(arg: &str) -> Option<Config> {
@@ -22,7 +18,7 @@ fn get_config(arg: &str) -> Option<Config> {
         return None;
     }
 
-    let config = match read_config_file(&std::path::Path::new(arg)) {
+    let config = match read_config_file(std::path::Path::new(arg)) {
         Ok(c) => c,
         Err(e) => {
             println!("Err {}", e);
@@ -49,9 +45,49 @@ async fn main() {
 
     //Retrieve config
     let args: Vec<String> = std::env::args().collect();
-    if args.len() != 2 {
+    if args.len() < 2 {
         tracing::error!("Ritual requires an input yaml file!");
         print_help();
+        return;
+    }
+
+    if args[1] == "replay" {
+        if args.len() < 4 {
+            tracing::error!("Replay requires an input yaml file and a run directory!");
+            print_help();
+            return;
+        }
+        let speed = match args.get(4).map(|s| s.parse::<ReplaySpeed>()).unwrap_or(Ok(ReplaySpeed::Max)) {
+            Ok(s) => s,
+            Err(e) => {
+                tracing::error!("{}", e);
+                return;
+            }
+        };
+        if let Some(config) = get_config(&args[2]) {
+            replay_run(config, std::path::Path::new(&args[3]), speed).await;
+        }
+        return;
+    }
+
+    if args[1] == "simulate" {
+        if args.len() < 3 {
+            tracing::error!("Simulate requires a project directory!");
+            print_help();
+            return;
+        }
+        let sim_config = match args.get(3) {
+            Some(path) => match read_simulation_config_file(std::path::Path::new(path)) {
+                Ok(c) => c,
+                Err(e) => {
+                    tracing::error!("{}", e);
+                    return;
+                }
+            },
+            None => SimulationConfig::default()
+        };
+        simulate_project(std::path::PathBuf::from(&args[2]), sim_config).await;
+        return;
     }
 
     let config = match get_config(&args[1]) {
@@ -59,79 +95,65 @@ async fn main() {
         None => return
     };
 
-    //Data channels
-    let (data_sender, data_reciever) = tokio::sync::mpsc::channel::<Bytes>(10);
-    let (event_sender, event_reciever) = tokio::sync::mpsc::channel::<notify::event::Event>(5);
-    let (shutdown_sender, mut shutdown_reciever) = tokio::sync::mpsc::channel::<i32>(1);
+    let shutdown = async {
+        match tokio::signal::ctrl_c().await {
+            Ok(()) => tracing::info!("Recieved a ctrl-c, shutting down."),
+            Err(e) => tracing::error!("Ctrl-c error: {}", e)
+        }
+    };
+
+    if let Err(e) = ritual::run(config, shutdown).await {
+        tracing::error!("{}", e);
+    }
+}
 
-    //Initialize the server, spawining server tasks
-    match run_server(&config.server_address, data_reciever).await {
-        Ok(_) => {},
+/*
+    Replay a finished run through the server. The replay starts once the first client connects,
+    and the server keeps running afterwards until a ctrl-c.
+ */
+async fn replay_run(config: Config, run_dir: &std::path::Path, speed: ReplaySpeed) {
+    let mut replay = match Replay::new(run_dir, &config, speed) {
+        Ok(r) => r,
         Err(e) => {
-            tracing::error!("Server initialization error: {}", e);
+            tracing::error!("Replay initialization error: {}", e);
             return;
         }
     };
 
-    //Initialize project
-    let mut project = match Project::new(&config.project_directory, event_reciever, data_sender) {
-        Ok(p) => p,
+    let (data_sender, data_reciever) = tokio::sync::mpsc::channel::<Bytes>(10);
+    let server = match run_server(&config.server_address, ServerOptions::new(&config), data_reciever).await {
+        Ok(s) => s,
         Err(e) => {
-            tracing::error!("Project initialization error: {}", e);
-            return
+            tracing::error!("Server initialization error: {}", e);
+            return;
         }
     };
 
-    //Spawn project, shutdown tasks
-
+    let mut n_connections = server.connections();
     tokio::spawn(async move {
-        match project.handle_events().await {
-            Ok(_) => {},
-            Err(e) => tracing::error!("Project error: {}", e)
+        tracing::info!("Waiting for a client to connect before replaying");
+        if n_connections.wait_for(|n| *n > 0).await.is_err() {
+            return;
         }
-    });
-
-    tokio::spawn(async move {
-        match tokio::signal::ctrl_c().await {
-            Ok(()) => {
-                tracing::info!("Recieved a ctrl-c, shutting down.");
-                shutdown_sender.send(1).await.unwrap();
-            }
-            Err(e) => {
-                tracing::error!("Ctrl-c error: {}", e);
-            }
+        match replay.run(&data_sender).await {
+            Ok(_) => {},
+            Err(e) => tracing::error!("Replay error: {}", e)
         }
     });
 
-    /*
-        Watcher is spawned differently. Notify is a synchronous crate, so we need to bridge
-        the synchronous code. This is done by using the blocking functionality of tokio channels,
-        and spawning a blocking task (essentially a blocking thread). This then serves as the "main"
-        thread of the app, and as such recieves any shutdown signals
-     */
-    let result = tokio::task::spawn_blocking(move || {
-            let mut watcher = match create_watcher(event_sender) {
-                Ok(w) => w,
-                Err(e) => {
-                    tracing::error!("Notify error: {}", e);
-                    return
-                }
-            };
-
-            match watcher.watch(&std::path::Path::new("test_project"), notify::RecursiveMode::Recursive) {
-                Err(e) => {
-                    tracing::error!("Notify error: {}", e);
-                    return
-                }
-                _ => {}
-            }
-
-            loop {
-                _ = shutdown_reciever.blocking_recv().unwrap();
-                break;
-            }
-    });
-
-    result.await.unwrap();
+    match tokio::signal::ctrl_c().await {
+        Ok(()) => tracing::info!("Recieved a ctrl-c, shutting down."),
+        Err(e) => tracing::error!("Ctrl-c error: {}", e)
+    }
+}
 
+//Write simulated data to a project directory until all of the configured runs are done. The simulator sleeps between writes, so it runs on a blocking thread.
+async fn simulate_project(project_dir: std::path::PathBuf, config: SimulationConfig) {
+    let sim_dir = project_dir.clone();
+    let result = tokio::task::spawn_blocking(move || Simulator::new(&sim_dir, config).and_then(|mut sim| sim.run())).await;
+    match result {
+        Ok(Ok(runs)) => tracing::info!("Simulated {} runs in {}", runs.len(), project_dir.display()),
+        Ok(Err(e)) => tracing::error!("Simulation error: {}", e),
+        Err(e) => tracing::error!("Simulation task failed: {}", e)
+    }
 }
@@ -0,0 +1,153 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+
+use crate::file::CompassHit;
+
+/*
+    HitMerger performs a k-way merge over the hit streams of several CompassFiles, emitting hits
+    globally sorted by timestamp. Each stream (source) is assumed to be time ordered on its own.
+
+    A hit can safely be emitted once every source has produced a hit at or beyond its timestamp.
+    Sources flush at different rates (and quiet channels may not flush at all), so a hit is also
+    released once it is older than the newest timestamp seen by more than the hold-back window.
+    Hits which arrive later than the window allows are still emitted, just out of order.
+ */
+#[derive(Debug)]
+pub struct HitMerger {
+    buffer: BinaryHeap<Reverse<OrderedHit>>,
+    source_marks: Vec<Option<u64>>, //Latest timestamp seen from each source
+    latest: u64, //Latest timestamp seen from any source
+    hold_back: u64
+}
+
+impl HitMerger {
+
+    pub fn new(n_sources: usize, hold_back: u64) -> Self {
+        HitMerger { buffer: BinaryHeap::new(), source_marks: vec![None; n_sources], latest: 0, hold_back }
+    }
+
+    //Register a new source (i.e. a new file), returning its index
+    pub fn add_source(&mut self) -> usize {
+        self.source_marks.push(None);
+        self.source_marks.len() - 1
+    }
+
+    pub fn push(&mut self, source: usize, hit: CompassHit) {
+        let mark = &mut self.source_marks[source];
+        *mark = Some(mark.map_or(hit.timestamp, |m| m.max(hit.timestamp)));
+        self.latest = self.latest.max(hit.timestamp);
+        self.buffer.push(Reverse(OrderedHit(hit)));
+    }
+
+    //Timestamp at or below which all buffered hits can be emitted
+    fn watermark(&self) -> u64 {
+        let window_mark = self.latest.saturating_sub(self.hold_back);
+        let stream_mark = self.source_marks.iter()
+            .try_fold(u64::MAX, |acc, mark| mark.map(|m| acc.min(m)))
+            .unwrap_or(0);
+        window_mark.max(stream_mark)
+    }
+
+    //Take all hits which are safe to emit, in time order
+    pub fn pop_ready(&mut self) -> Vec<CompassHit> {
+        let watermark = self.watermark();
+        let mut ready = vec![];
+        while let Some(Reverse(top)) = self.buffer.peek() {
+            if top.0.timestamp > watermark {
+                break;
+            }
+            ready.push(self.buffer.pop().unwrap().0.0);
+        }
+        ready
+    }
+
+    //Take all buffered hits, in time order. Used when the sources are finished (i.e. end of run).
+    pub fn flush(&mut self) -> Vec<CompassHit> {
+        let mut hits = Vec::with_capacity(self.buffer.len());
+        while let Some(Reverse(hit)) = self.buffer.pop() {
+            hits.push(hit.0);
+        }
+        hits
+    }
+}
+
+//Ordering wrapper for the heap; ties in time are broken by board then channel to keep output deterministic
+#[derive(Debug)]
+struct OrderedHit(CompassHit);
+
+impl OrderedHit {
+    fn key(&self) -> (u64, u16, u16) {
+        (self.0.timestamp, self.0.board, self.0.channel)
+    }
+}
+
+impl PartialEq for OrderedHit {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for OrderedHit {}
+
+impl PartialOrd for OrderedHit {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for OrderedHit {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hit(channel: u16, timestamp: u64) -> CompassHit {
+        CompassHit { channel, timestamp, ..Default::default() }
+    }
+
+    fn timestamps(hits: &[CompassHit]) -> Vec<u64> {
+        hits.iter().map(|h| h.timestamp).collect()
+    }
+
+    #[test]
+    fn merges_when_all_sources_advance() {
+        let mut merger = HitMerger::new(2, 1_000_000);
+        [10, 30, 50].iter().for_each(|t| merger.push(0, hit(0, *t)));
+        assert!(merger.pop_ready().is_empty()); //Source 1 has not reported yet
+
+        [20, 40].iter().for_each(|t| merger.push(1, hit(1, *t)));
+        assert_eq!(timestamps(&merger.pop_ready()), vec![10, 20, 30, 40]);
+
+        merger.push(1, hit(1, 60));
+        assert_eq!(timestamps(&merger.pop_ready()), vec![50]);
+        assert_eq!(timestamps(&merger.flush()), vec![60]);
+    }
+
+    #[test]
+    fn hold_back_releases_quiet_sources() {
+        let mut merger = HitMerger::new(2, 100);
+        [10, 80, 150, 250].iter().for_each(|t| merger.push(0, hit(0, *t)));
+        assert_eq!(timestamps(&merger.pop_ready()), vec![10, 80, 150]);
+
+        merger.push(1, hit(1, 120)); //Late, but within what the merger can still handle
+        merger.push(0, hit(0, 400));
+        assert_eq!(timestamps(&merger.pop_ready()), vec![120, 250]);
+    }
+
+    #[test]
+    fn added_sources_hold_the_merge() {
+        let mut merger = HitMerger::new(1, 1_000);
+        merger.push(0, hit(0, 10));
+        assert_eq!(timestamps(&merger.pop_ready()), vec![10]);
+
+        let source = merger.add_source();
+        merger.push(0, hit(0, 20));
+        assert!(merger.pop_ready().is_empty());
+        merger.push(source, hit(1, 15));
+        assert_eq!(timestamps(&merger.pop_ready()), vec![15]);
+    }
+}
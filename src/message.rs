@@ -1,41 +1,654 @@
 use bytes::{Bytes};
 
+use crate::event::CompassEvent;
+use crate::file::{ChannelId, CompassDataType, CompassHit};
+use crate::run::{RunId, RunInfo};
+use crate::settings::CompassSettings;
+
+/*
+    Wire protocol
+
+    Every Message is sent as a single frame. All multi-byte fields are little endian, regardless of the
+    platform ritual runs on. A frame is laid out as:
+
+        magic        [u8; 4]  Always "RTUL", marks the start of a frame
+        version      u16      PROTOCOL_VERSION; clients should refuse versions they do not know
+        kind         u16      MessageKind
+        frame_size   u64      Size of the entire frame in bytes, including this header
+        board        u16      Source board, or SOURCE_ANY if the data comes from more than one channel
+        channel      u16      Source channel, or SOURCE_ANY
+        data_type    u16      The CoMPASS header (CompassDataType) used to encode the hits
+        hit_size     u32      Size of a single hit in bytes, or 0 if hits are variable length (waves)
+        num_hits     u64      Number of hits (or events) in the payload
+        run_number   u32      Run the data belongs to, or RUN_NUMBER_NONE if it does not belong to a run
+        file_length  u16      Length of the source file name
+        file         [u8]     Source file name (UTF-8), empty if the data comes from more than one file
+        origin_length u16     Length of the origin name
+        origin       [u8]     Data subdirectory the data came from (UTF-8), i.e. UNFILTERED
+        payload      [u8]     The data, frame_size - (FRAME_HEADER_SIZE + file_length + origin_length) bytes
+
+    Run control frames (RunStart, RunStop) and Metadata frames have no source channel or origin; file is the name
+    of the run directory and the payload is a RunInfo or the CoMPASS settings (JSON) respectively.
+    Rejected frames have no source and no run; the payload is the reason (UTF-8).
+    Heartbeat frames have no source; run_number is the latest run and the payload is a Heartbeat.
+
+    Clients may send command frames (Subscribe, Unsubscribe, SelectStreams, RequestMetadata, HeartbeatAck) to the server
+    in the same layout; only the kind and payload are used. See subscription::Command for the payloads.
+ */
+
+///First bytes of every frame
+pub const PROTOCOL_MAGIC: [u8; 4] = *b"RTUL";
+///Bumped whenever the frame layout changes
+pub const PROTOCOL_VERSION: u16 = 1;
+///Board or channel of a frame which is not from a single channel
+pub const SOURCE_ANY: u16 = 0xFFFF;
+///Run number of a frame which does not belong to a run
+pub const RUN_NUMBER_NONE: u32 = 0xFFFFFFFF;
+
+///Size of the fixed portion of the frame header (everything except the file name, origin, and payload)
+pub const FRAME_HEADER_SIZE: usize = 4 + 2 + 2 + 8 + 2 + 2 + 2 + 4 + 8 + 4 + 2 + 2;
+
+/**
+    Size of a hit in a DecodedHits payload: board u16, channel u16, timestamp u64, energy u16, energy_short u16,
+    energy_calibrated f64, flags u32. Every field is present whatever the data type; fields the data type
+    does not have are 0. Waveform samples are not included.
+ */
+pub const DECODED_HIT_SIZE: usize = 2 + 2 + 8 + 2 + 2 + 8 + 4;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MessageError {
+    BadMagic([u8; 4]),
+    UnsupportedVersion(u16),
+    UnknownKind(u16),
+    Incomplete(usize), //Number of bytes still needed to finish the frame
+    InvalidSource,
+    InvalidSize(usize),
+    FieldTooLarge(&'static str, usize) //A field of the Message does not fit in its header field
+}
+
+impl std::fmt::Display for MessageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::BadMagic(magic) => write!(f, "Frame did not start with the ritual magic number, found {:?}", magic),
+            Self::UnsupportedVersion(v) => write!(f, "Frame has protocol version {}, but only version {} is supported", v, PROTOCOL_VERSION),
+            Self::UnknownKind(k) => write!(f, "Frame has unknown message kind {}", k),
+            Self::Incomplete(n) => write!(f, "Frame is incomplete, at least {} more bytes are needed", n),
+            Self::InvalidSource => write!(f, "Frame source file or origin name is malformed"),
+            Self::InvalidSize(n) => write!(f, "Frame size {} is smaller than the frame header", n),
+            Self::FieldTooLarge(field, n) => write!(f, "Message {} of {} is too large for the frame header", field, n)
+        }
+    }
+}
+
+impl std::error::Error for MessageError {
+
+}
+
+/**
+    The kind of data carried by a Message.
+    Hits: the data buffer is CoMPASS records, num_hits is the number of records.
+    Events: the data buffer is built events, each a u32 hit count followed by that many CoMPASS records.
+    num_hits is the number of events.
+    RunStart/RunStop: a run began or ended, the data buffer is the RunInfo of the run. num_hits is 0.
+    Metadata: the acquisition settings of the run (CompassSettings) as UTF-8 JSON. num_hits is 0.
+    Rejected: the server will not serve the connection (it is full, or the client was evicted to make room)
+    and closes it after this frame. The data buffer is the reason as UTF-8. num_hits is 0.
+    DecodedHits: the data buffer is hits in the fixed layout of DECODED_HIT_SIZE, num_hits is the number of hits.
+    data_type is that of the CoMPASS data the hits were decoded from.
+    Heartbeat: sent periodically so that clients can tell an idle server from a dead one. The data buffer is a
+    Heartbeat (server time, run state, and hits per channel in the run). num_hits is 0.
+    Subscribe, Unsubscribe, SelectStreams, RequestMetadata, HeartbeatAck: commands sent by clients to the server.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
+pub enum MessageKind {
+    Hits = 0,
+    Events = 1,
+    RunStart = 2,
+    RunStop = 3,
+    Metadata = 4,
+    Rejected = 5,
+    DecodedHits = 6,
+    Subscribe = 7,
+    Unsubscribe = 8,
+    SelectStreams = 9,
+    RequestMetadata = 10,
+    Heartbeat = 11,
+    HeartbeatAck = 12
+}
+
+impl TryFrom<u16> for MessageKind {
+    type Error = MessageError;
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(MessageKind::Hits),
+            1 => Ok(MessageKind::Events),
+            2 => Ok(MessageKind::RunStart),
+            3 => Ok(MessageKind::RunStop),
+            4 => Ok(MessageKind::Metadata),
+            5 => Ok(MessageKind::Rejected),
+            6 => Ok(MessageKind::DecodedHits),
+            7 => Ok(MessageKind::Subscribe),
+            8 => Ok(MessageKind::Unsubscribe),
+            9 => Ok(MessageKind::SelectStreams),
+            10 => Ok(MessageKind::RequestMetadata),
+            11 => Ok(MessageKind::Heartbeat),
+            12 => Ok(MessageKind::HeartbeatAck),
+            _ => Err(MessageError::UnknownKind(value))
+        }
+    }
+}
+
+/**
+    Where the data in a Message came from. Messages built from a single CoMPASS file carry the file name
+    and its board/channel; merged data (time ordered hits, events) has no single source. The origin is the
+    data subdirectory of the run (UNFILTERED, FILTERED, ...).
+ */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MessageSource {
+    pub channel: Option<ChannelId>,
+    pub file: String,
+    pub origin: String
+}
+
+/**
+    Heartbeat is the payload of a Heartbeat frame, sent to every client periodically whether or not there is data.
+    server_time is the unix time in milliseconds, running is true between the RunStart and RunStop of the latest run,
+    and counters are the number of hits sent for each channel in the latest run. Hits which came from merged
+    frames are counted by their own channel. Clients ack a heartbeat with Command::HeartbeatAck(sequence).
+
+    Encoded (little endian) as sequence (u64), server_time (u64), running (u8), the number of counters (u32),
+    then board (u16), channel (u16), and hits (u64) per counter. The run number is in the frame header.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Heartbeat {
+    pub sequence: u64,
+    pub server_time: u64,
+    pub run_number: Option<u32>,
+    pub running: bool,
+    pub counters: Vec<(ChannelId, u64)>
+}
+
+impl Heartbeat {
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(21 + 12 * self.counters.len());
+        bytes.extend_from_slice(&self.sequence.to_le_bytes());
+        bytes.extend_from_slice(&self.server_time.to_le_bytes());
+        bytes.push(self.running as u8);
+        bytes.extend_from_slice(&(self.counters.len() as u32).to_le_bytes());
+        for (id, hits) in self.counters.iter() {
+            bytes.extend_from_slice(&id.board.to_le_bytes());
+            bytes.extend_from_slice(&id.channel.to_le_bytes());
+            bytes.extend_from_slice(&hits.to_le_bytes());
+        }
+        bytes
+    }
+
+    ///Read the Heartbeat in a Message. None if it is not a Heartbeat Message or its payload is malformed.
+    pub fn from_message(message: &Message) -> Option<Heartbeat> {
+        if message.kind != MessageKind::Heartbeat || message.data.len() < 21 {
+            return None;
+        }
+        let data = &message.data;
+        let n_counters = u32::from_le_bytes(data[17..21].try_into().unwrap()) as usize;
+        if data.len() != 21 + 12 * n_counters {
+            return None;
+        }
+        let counters = data[21..].chunks_exact(12).map(|counter| {
+            let id = ChannelId {
+                board: u16::from_le_bytes(counter[0..2].try_into().unwrap()),
+                channel: u16::from_le_bytes(counter[2..4].try_into().unwrap())
+            };
+            (id, u64::from_le_bytes(counter[4..12].try_into().unwrap()))
+        }).collect();
+        Some(Heartbeat {
+            sequence: u64::from_le_bytes(data[0..8].try_into().unwrap()),
+            server_time: u64::from_le_bytes(data[8..16].try_into().unwrap()),
+            run_number: message.run_number,
+            running: data[16] != 0,
+            counters
+        })
+    }
+}
+
 /*
     Message is the fundamental data structure transmitted by the server.
-    It contains a size, hit size, data type, and a data buffer
+    It contains a kind, source, run number, hit size, number of hits, data type, and a data buffer.
+    Files in waves mode have variable length records, which is indicated by a hit size of 0;
+    in that case each record must be walked using the sample count stored in the record.
  */
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Message {
-    pub size: u64, //Size of the message (total including size of size and data_type)
-    pub hit_size: u64, //Size of a single hit in the data buffer 
+    pub kind: MessageKind, //What the data buffer contains
+    pub source: MessageSource, //Where the data came from
+    pub run_number: Option<u32>, //Run the data belongs to
+    pub hit_size: u64, //Size of a single hit in the data buffer, or 0 if hits are variable length (waves)
+    pub num_hits: u64, //Number of hits (or events) in the data buffer
     pub data_type: u16, //The CoMPASS header, useful for parsing the hits
-    pub data: Vec<u8> //Actual data buffer. Size of the buffer in bytes is size - (64*2 + 16)
+    pub data: Vec<u8> //Actual data buffer
 }
 
 impl Default for Message {
 
-    //The minimum size of the message is 64 * 2 + 16 for the size of the memebers size, hit_size, and data_type
     fn default() -> Message {
-        Message { size: 64 * 2 + 16, hit_size: 0, data_type: 0, data: vec![] }
+        Message { kind: MessageKind::Hits, source: MessageSource::default(), run_number: None, hit_size: 0, num_hits: 0, data_type: 0, data: vec![] }
+    }
+}
+
+impl Message {
+
+    ///Build a Message from decoded hits, encoding them in the CoMPASS layout of the given data type
+    pub fn from_hits(data_type: CompassDataType, hits: &[CompassHit]) -> Message {
+        let mut message = Message { data_type: data_type.bits(), num_hits: hits.len() as u64, ..Default::default() };
+        if !data_type.contains(CompassDataType::WAVES) {
+            message.hit_size = data_type.hit_size() as u64;
+        }
+        hits.iter().for_each(|hit| message.data.append(&mut hit.encode(data_type)));
+        message
+    }
+
+    ///Build an Events Message from built events, encoding their hits in the CoMPASS layout of the given data type
+    pub fn from_events(data_type: CompassDataType, events: &[CompassEvent]) -> Message {
+        let mut message = Message { kind: MessageKind::Events, data_type: data_type.bits(), num_hits: events.len() as u64, ..Default::default() };
+        if !data_type.contains(CompassDataType::WAVES) {
+            message.hit_size = data_type.hit_size() as u64;
+        }
+        events.iter().for_each(|event| message.data.append(&mut event.encode(data_type)));
+        message
+    }
+
+    ///Build a run control Message (RunStart or RunStop) for a run
+    pub fn from_run_info(kind: MessageKind, run: &RunId, info: &RunInfo) -> Message {
+        let source = MessageSource { file: run.name.clone(), ..Default::default() };
+        Message { kind, source, run_number: Some(run.number), data: info.encode(), ..Default::default() }
+    }
+
+    ///Build a Metadata Message holding the acquisition settings of a run
+    pub fn from_settings(run: &RunId, settings: &CompassSettings) -> Message {
+        let source = MessageSource { file: run.name.clone(), ..Default::default() };
+        Message { kind: MessageKind::Metadata, source, run_number: Some(run.number), data: settings.to_json(), ..Default::default() }
+    }
+
+    ///Build the Message telling a client why its connection is being closed
+    pub fn rejection(reason: &str) -> Message {
+        Message { kind: MessageKind::Rejected, data: reason.as_bytes().to_vec(), ..Default::default() }
+    }
+
+    ///Build a Heartbeat Message
+    pub fn from_heartbeat(heartbeat: &Heartbeat) -> Message {
+        Message { kind: MessageKind::Heartbeat, run_number: heartbeat.run_number, data: heartbeat.encode(), ..Default::default() }
+    }
+
+    ///Build a DecodedHits Message from a Hits Message, keeping its source and run
+    pub fn to_decoded(&self) -> Option<Message> {
+        if self.kind != MessageKind::Hits {
+            return None;
+        }
+        let hits = self.hits();
+        let mut data = Vec::with_capacity(hits.len() * DECODED_HIT_SIZE);
+        hits.iter().for_each(|hit| encode_decoded_hit(hit, &mut data));
+        Some(Message { kind: MessageKind::DecodedHits, hit_size: DECODED_HIT_SIZE as u64, num_hits: hits.len() as u64, data, ..self.clone_header() })
+    }
+
+    ///The hits in a Hits or DecodedHits Message; empty for any other kind
+    pub fn hits(&self) -> Vec<CompassHit> {
+        match self.kind {
+            MessageKind::Hits => CompassHit::decode_all(CompassDataType::from_bits_truncate(self.data_type), &self.data),
+            MessageKind::DecodedHits => self.data.chunks_exact(DECODED_HIT_SIZE).map(decode_decoded_hit).collect(),
+            _ => vec![]
+        }
+    }
+
+    ///The events in an Events Message; empty for any other kind
+    pub fn events(&self) -> Vec<CompassEvent> {
+        match self.kind {
+            MessageKind::Events => CompassEvent::decode_all(CompassDataType::from_bits_truncate(self.data_type), &self.data),
+            _ => vec![]
+        }
+    }
+
+    ///A Message with the same kind, source, run, and data type, but no data
+    pub fn clone_header(&self) -> Message {
+        Message { kind: self.kind, source: self.source.clone(), run_number: self.run_number, hit_size: self.hit_size, data_type: self.data_type, ..Default::default() }
+    }
+
+    ///Size of the Message as a frame on the wire
+    pub fn frame_size(&self) -> usize {
+        FRAME_HEADER_SIZE + self.source.file.len() + self.source.origin.len() + self.data.len()
     }
+
+    ///Append the Message to the buffer as a frame. Nothing is appended if a field does not fit in the header.
+    pub fn encode(&self, buffer: &mut Vec<u8>) -> Result<(), MessageError> {
+        let (board, channel) = match self.source.channel {
+            Some(id) => (id.board, id.channel),
+            None => (SOURCE_ANY, SOURCE_ANY)
+        };
+        let hit_size = u32::try_from(self.hit_size).map_err(|_| MessageError::FieldTooLarge("hit size", self.hit_size as usize))?;
+        let file_len = u16::try_from(self.source.file.len()).map_err(|_| MessageError::FieldTooLarge("file name length", self.source.file.len()))?;
+        let origin_len = u16::try_from(self.source.origin.len()).map_err(|_| MessageError::FieldTooLarge("origin length", self.source.origin.len()))?;
+
+        buffer.reserve(self.frame_size());
+        buffer.extend_from_slice(&PROTOCOL_MAGIC);
+        buffer.extend_from_slice(&PROTOCOL_VERSION.to_le_bytes());
+        buffer.extend_from_slice(&(self.kind as u16).to_le_bytes());
+        buffer.extend_from_slice(&(self.frame_size() as u64).to_le_bytes());
+        buffer.extend_from_slice(&board.to_le_bytes());
+        buffer.extend_from_slice(&channel.to_le_bytes());
+        buffer.extend_from_slice(&self.data_type.to_le_bytes());
+        buffer.extend_from_slice(&hit_size.to_le_bytes());
+        buffer.extend_from_slice(&self.num_hits.to_le_bytes());
+        buffer.extend_from_slice(&self.run_number.unwrap_or(RUN_NUMBER_NONE).to_le_bytes());
+        buffer.extend_from_slice(&file_len.to_le_bytes());
+        buffer.extend_from_slice(self.source.file.as_bytes());
+        buffer.extend_from_slice(&origin_len.to_le_bytes());
+        buffer.extend_from_slice(self.source.origin.as_bytes());
+        buffer.extend_from_slice(&self.data);
+        Ok(())
+    }
+
+    /**
+        Decode a single frame from the start of the buffer. Returns the Message and the number of bytes
+        consumed. If the buffer does not yet hold the whole frame, MessageError::Incomplete is returned
+        and the caller should try again once more data has arrived.
+     */
+    pub fn decode(buffer: &[u8]) -> Result<(Message, usize), MessageError> {
+        let FrameHeader { kind, frame_size, channel, data_type, hit_size, num_hits, run_number } = Message::peek(buffer)?;
+        if buffer.len() < frame_size {
+            return Err(MessageError::Incomplete(frame_size - buffer.len()));
+        }
+
+        //The file and origin follow the fixed fields read by peek
+        let mut reader = FrameReader { buffer, position: FRAME_HEADER_SIZE - 4 };
+        let file = reader.take_string(frame_size)?;
+        let origin = reader.take_string(frame_size)?;
+        let data = buffer[reader.position..frame_size].to_vec();
+
+        Ok((Message { kind, source: MessageSource { channel, file, origin }, run_number, hit_size, num_hits, data_type, data }, frame_size))
+    }
+}
+
+/**
+    The part of a frame header needed to route the frame without decoding it
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub kind: MessageKind,
+    pub frame_size: usize,
+    pub channel: Option<ChannelId>,
+    pub data_type: u16,
+    pub hit_size: u64,
+    pub num_hits: u64,
+    pub run_number: Option<u32>
+}
+
+impl Message {
+    /**
+        Read the fixed portion of the header of the frame at the start of the buffer, checking its magic number,
+        version, and kind. The buffer only needs to hold the fixed portion; the whole frame is frame_size bytes.
+     */
+    pub fn peek(buffer: &[u8]) -> Result<FrameHeader, MessageError> {
+        if buffer.len() < FRAME_HEADER_SIZE {
+            return Err(MessageError::Incomplete(FRAME_HEADER_SIZE - buffer.len()));
+        }
+
+        let mut reader = FrameReader { buffer, position: 0 };
+        let magic: [u8; 4] = reader.take();
+        if magic != PROTOCOL_MAGIC {
+            return Err(MessageError::BadMagic(magic));
+        }
+        let version = u16::from_le_bytes(reader.take());
+        if version != PROTOCOL_VERSION {
+            return Err(MessageError::UnsupportedVersion(version));
+        }
+        let kind = MessageKind::try_from(u16::from_le_bytes(reader.take()))?;
+        let frame_size = u64::from_le_bytes(reader.take()) as usize;
+        if frame_size < FRAME_HEADER_SIZE {
+            return Err(MessageError::InvalidSize(frame_size));
+        }
+        let board = u16::from_le_bytes(reader.take());
+        let channel = u16::from_le_bytes(reader.take());
+        let data_type = u16::from_le_bytes(reader.take());
+        let hit_size = u32::from_le_bytes(reader.take()) as u64;
+        let num_hits = u64::from_le_bytes(reader.take());
+        let run_number = u32::from_le_bytes(reader.take());
+
+        let channel = if board == SOURCE_ANY && channel == SOURCE_ANY {
+            None
+        } else {
+            Some(ChannelId { board, channel })
+        };
+
+        let run_number = (run_number != RUN_NUMBER_NONE).then_some(run_number);
+
+        Ok(FrameHeader { kind, frame_size, channel, data_type, hit_size, num_hits, run_number })
+    }
+}
+
+fn encode_decoded_hit(hit: &CompassHit, buffer: &mut Vec<u8>) {
+    buffer.extend_from_slice(&hit.board.to_le_bytes());
+    buffer.extend_from_slice(&hit.channel.to_le_bytes());
+    buffer.extend_from_slice(&hit.timestamp.to_le_bytes());
+    buffer.extend_from_slice(&hit.energy.to_le_bytes());
+    buffer.extend_from_slice(&hit.energy_short.to_le_bytes());
+    buffer.extend_from_slice(&hit.energy_calibrated.to_le_bytes());
+    buffer.extend_from_slice(&hit.flags.to_le_bytes());
+}
+
+fn decode_decoded_hit(buffer: &[u8]) -> CompassHit {
+    let mut reader = FrameReader { buffer, position: 0 };
+    CompassHit {
+        board: u16::from_le_bytes(reader.take()),
+        channel: u16::from_le_bytes(reader.take()),
+        timestamp: u64::from_le_bytes(reader.take()),
+        energy: u16::from_le_bytes(reader.take()),
+        energy_short: u16::from_le_bytes(reader.take()),
+        energy_calibrated: f64::from_le_bytes(reader.take()),
+        flags: u32::from_le_bytes(reader.take()),
+        ..Default::default()
+    }
+}
+
+//Sequential reader for the frame header. Bounds of the fixed portion are checked by the caller.
+struct FrameReader<'a> {
+    buffer: &'a [u8],
+    position: usize
+}
+
+impl FrameReader<'_> {
+    fn take<const N: usize>(&mut self) -> [u8; N] {
+        let mut bytes = [0; N];
+        bytes.copy_from_slice(&self.buffer[self.position..(self.position + N)]);
+        self.position += N;
+        bytes
+    }
+
+    //Read a length prefixed UTF-8 string which must end within the frame
+    fn take_string(&mut self, frame_size: usize) -> Result<String, MessageError> {
+        if self.position + 2 > frame_size {
+            return Err(MessageError::InvalidSource);
+        }
+        let length = u16::from_le_bytes(self.take()) as usize;
+        if self.position + length > frame_size {
+            return Err(MessageError::InvalidSource);
+        }
+        let string = match std::str::from_utf8(&self.buffer[self.position..(self.position + length)]) {
+            Ok(s) => s.to_string(),
+            Err(_) => return Err(MessageError::InvalidSource)
+        };
+        self.position += length;
+        Ok(string)
+    }
+}
+
+//Split a buffer which holds a whole number of frames into the frames, without decoding them
+pub fn split_frames(data: &Bytes) -> Result<Vec<(FrameHeader, Bytes)>, MessageError> {
+    let mut frames = vec![];
+    let mut position = 0;
+    while position < data.len() {
+        let header = Message::peek(&data[position..])?;
+        if position + header.frame_size > data.len() {
+            return Err(MessageError::Incomplete(position + header.frame_size - data.len()));
+        }
+        frames.push((header, data.slice(position..(position + header.frame_size))));
+        position += header.frame_size;
+    }
+    Ok(frames)
 }
 
-//Convert Message to a single contiguous Byte vec.
+///Convert Message to a single contiguous Byte vec. Messages which cannot be encoded are logged and left out.
 pub fn convert_messages_to_bytes(mess_list: Vec<Message>) -> Bytes {
 
     let mut binary: Vec<u8> = Vec::new();
     let mut total_data: usize = 0;
     mess_list.iter().for_each(|mess| {
-        total_data += mess.size as usize
+        total_data += mess.frame_size()
     });
     binary.reserve(total_data);
-    for mut mess in mess_list {
-        binary.append(&mut mess.size.to_ne_bytes().to_vec());
-        binary.append(&mut mess.hit_size.to_ne_bytes().to_vec());
-        binary.append(&mut mess.data_type.to_ne_bytes().to_vec());
-        binary.append(&mut mess.data);
+    for mess in mess_list {
+        if let Err(e) = mess.encode(&mut binary) {
+            tracing::error!("Dropping a {:?} message: {}", mess.kind, e);
+        }
     }
 
     Bytes::from(binary)
-}
\ No newline at end of file
+}
+
+///Decode every frame in a buffer which holds a whole number of frames (i.e. the output of convert_messages_to_bytes)
+pub fn convert_bytes_to_messages(mut binary: &[u8]) -> Result<Vec<Message>, MessageError> {
+    let mut mess_list = vec![];
+    while !binary.is_empty() {
+        let (mess, size) = Message::decode(binary)?;
+        mess_list.push(mess);
+        binary = &binary[size..];
+    }
+    Ok(mess_list)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file_message() -> Message {
+        let data_type = CompassDataType::ENERGY | CompassDataType::ENERGY_SHORT;
+        let hits = vec![
+            CompassHit { board: 1, channel: 3, timestamp: 100, energy: 20, energy_short: 5, ..Default::default() },
+            CompassHit { board: 1, channel: 3, timestamp: 250, energy: 900, energy_short: 40, ..Default::default() }
+        ];
+        let mut message = Message::from_hits(data_type, &hits);
+        message.source = MessageSource { channel: Some(ChannelId { board: 1, channel: 3 }), file: "DataR_CH3@V1730_89_run_2.BIN".to_string(), origin: "UNFILTERED".to_string() };
+        message.run_number = Some(2);
+        message
+    }
+
+    fn event_message() -> Message {
+        let hits = vec![CompassHit { board: 0, channel: 0, timestamp: 10, energy: 7, ..Default::default() }];
+        Message::from_events(CompassDataType::ENERGY, &[CompassEvent { hits }])
+    }
+
+    fn run_id() -> RunId {
+        RunId { name: "run_4".to_string(), number: 4 }
+    }
+
+    fn run_message() -> Message {
+        let info = RunInfo { run_number: 4, start_time: 1000, stop_time: Some(2000), total_hits: 12 };
+        Message::from_run_info(MessageKind::RunStop, &run_id(), &info)
+    }
+
+    #[test]
+    fn round_trips_frames() {
+        let metadata = Message::from_settings(&run_id(), &CompassSettings::default());
+        let messages = vec![file_message(), event_message(), run_message(), metadata, Message::rejection("Server is full"), Message::default()];
+        let bytes = convert_messages_to_bytes(messages.clone());
+        assert_eq!(bytes.len(), messages.iter().map(|m| m.frame_size()).sum::<usize>());
+        assert_eq!(convert_bytes_to_messages(&bytes).unwrap(), messages);
+    }
+
+    #[test]
+    fn round_trips_heartbeats() {
+        let heartbeat = Heartbeat {
+            sequence: 9,
+            server_time: 1_700_000_000_000,
+            run_number: Some(4),
+            running: true,
+            counters: vec![(ChannelId { board: 0, channel: 1 }, 3), (ChannelId { board: 1, channel: 0 }, 2)]
+        };
+        let bytes = convert_messages_to_bytes(vec![Message::from_heartbeat(&heartbeat)]);
+        let decoded = convert_bytes_to_messages(&bytes).unwrap();
+        assert_eq!(decoded[0].kind, MessageKind::Heartbeat);
+        assert_eq!(Heartbeat::from_message(&decoded[0]), Some(heartbeat));
+
+        let mut truncated = decoded[0].clone();
+        truncated.data.pop();
+        assert_eq!(Heartbeat::from_message(&truncated), None);
+        assert_eq!(Heartbeat::from_message(&Message::default()), None);
+    }
+
+    #[test]
+    fn splits_and_decodes_hits() {
+        let messages = vec![file_message(), run_message(), file_message().to_decoded().unwrap()];
+        let bytes = convert_messages_to_bytes(messages.clone());
+        let frames = split_frames(&bytes).unwrap();
+        let kinds: Vec<MessageKind> = frames.iter().map(|(header, _)| header.kind).collect();
+        assert_eq!(kinds, vec![MessageKind::Hits, MessageKind::RunStop, MessageKind::DecodedHits]);
+        assert_eq!(frames[0].0.channel, file_message().source.channel);
+        assert_eq!(Message::decode(&frames[1].1).unwrap().0, messages[1]);
+
+        let decoded = &messages[2];
+        assert_eq!((decoded.hit_size, decoded.num_hits, decoded.data.len()), (DECODED_HIT_SIZE as u64, 2, 2 * DECODED_HIT_SIZE));
+        assert_eq!(decoded.hits(), file_message().hits());
+        assert!(matches!(split_frames(&bytes.slice(..bytes.len() - 1)), Err(MessageError::Incomplete(1))));
+    }
+
+    #[test]
+    fn header_is_little_endian() {
+        let message = file_message();
+        let mut bytes = vec![];
+        message.encode(&mut bytes).unwrap();
+        assert_eq!(&bytes[0..4], b"RTUL");
+        assert_eq!(&bytes[4..6], &[1, 0]);
+        assert_eq!(&bytes[6..8], &[0, 0]);
+        assert_eq!(&bytes[8..16], &(bytes.len() as u64).to_le_bytes());
+        assert_eq!(&bytes[16..20], &[1, 0, 3, 0]);
+        assert_eq!(&bytes[20..22], &[0x05, 0x00]);
+        assert_eq!(&bytes[22..26], &[20, 0, 0, 0]);
+        assert_eq!(&bytes[26..34], &[2, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(&bytes[34..38], &[2, 0, 0, 0]);
+    }
+
+    #[test]
+    fn rejects_fields_too_large_for_the_header() {
+        let mut bytes = vec![];
+        let mut message = file_message();
+        message.hit_size = u32::MAX as u64 + 1;
+        assert_eq!(message.encode(&mut bytes), Err(MessageError::FieldTooLarge("hit size", u32::MAX as usize + 1)));
+        let mut message = file_message();
+        message.source.origin = "A".repeat(u16::MAX as usize + 1);
+        assert!(matches!(message.encode(&mut bytes), Err(MessageError::FieldTooLarge("origin length", _))));
+        assert!(bytes.is_empty());
+
+        let bytes = convert_messages_to_bytes(vec![message, run_message()]);
+        assert_eq!(convert_bytes_to_messages(&bytes).unwrap(), vec![run_message()]);
+    }
+
+    #[test]
+    fn detects_partial_and_corrupt_frames() {
+        let bytes = convert_messages_to_bytes(vec![file_message()]);
+        assert!(matches!(Message::decode(&bytes[..10]), Err(MessageError::Incomplete(_))));
+        assert_eq!(Message::decode(&bytes[..bytes.len() - 3]).unwrap_err(), MessageError::Incomplete(3));
+
+        let mut corrupt = bytes.to_vec();
+        corrupt[0] = b'X';
+        assert!(matches!(Message::decode(&corrupt), Err(MessageError::BadMagic(_))));
+
+        let mut unknown = bytes.to_vec();
+        unknown[6] = 42;
+        assert_eq!(Message::decode(&unknown).unwrap_err(), MessageError::UnknownKind(42));
+
+        let mut future = bytes.to_vec();
+        future[4] = 99;
+        assert_eq!(Message::decode(&future).unwrap_err(), MessageError::UnsupportedVersion(99));
+    }
+}
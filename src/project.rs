@@ -1,17 +1,14 @@
 use tokio::sync::mpsc::{Receiver, Sender};
 use bytes::Bytes;
 use std::path::{Path, PathBuf};
-use notify::event::{Event, EventKind, CreateKind, ModifyKind};
+use std::time::{Duration, SystemTime};
+use notify::event::{Event, EventKind, CreateKind, ModifyKind, AccessKind, AccessMode};
 
-use crate::file::{CompassFile, CompassFileError};
-use crate::message::{Message, convert_messages_to_bytes};
-
-/*
-    This file is kinda busy. May need a refactor.
- */
-
-//File extension of CAEN CoMPASS binary data files
-const COMPASS_BINARY_EXT: &str = ".BIN";
+use crate::config::{AttachMode, Config, ReadMode};
+use crate::file::CompassFileError;
+use crate::message::{Message, MessageKind, convert_messages_to_bytes};
+use crate::run::{ActiveRun, RunId, RunInfo, RunOptions, RunPattern, is_compass_binary};
+use crate::settings::CompassSettings;
 
 #[derive(Debug)]
 pub enum ProjectError {
@@ -49,81 +46,180 @@ impl std::error::Error for ProjectError {
 }
 
 /*
-    Check if the given Path is a run directory. This does not check if the Path is a directory.
-    Merely checks if the Path has the correct name format to be a run. (which should be run_#)
+    Find the most recent run directory in the project; the one with the highest run number,
+    or the most recently modified if run numbers are equal. Run directories may be nested below the
+    project directory (the watch is recursive), so subdirectories are searched too, but not run directories themselves.
  */
-fn is_run_dir(dir: &Path) -> bool {
-    dir.file_name()
-       .expect("Cannot retrieve directory name at is_run_dir")
-       .to_str()
-       .expect("Could not convert directory name to str at is_run_dir")
-       .contains("run_")
+fn find_latest_run_dir(project_path: &Path, pattern: &RunPattern) -> Result<Option<(RunId, PathBuf)>, ProjectError> {
+    let mut latest: Option<(u32, std::time::SystemTime, RunId, PathBuf)> = None;
+    let mut directories = vec![project_path.to_path_buf()];
+    while let Some(directory) = directories.pop() {
+        for item in directory.read_dir()? {
+            let item = item?;
+            let path = item.path();
+            let id = match pattern.parse(&path) {
+                Some(id) if path.is_dir() => id,
+                //Symlinks are not followed, so that a link loop can't trap the search
+                _ => {
+                    if item.file_type()?.is_dir() {
+                        directories.push(path);
+                    }
+                    continue;
+                }
+            };
+            let key = (id.number, item.metadata()?.modified()?);
+            if latest.as_ref().is_none_or(|(number, modified, _, _)| key > (*number, *modified)) {
+                latest = Some((key.0, key.1, id, path));
+            }
+        }
+    }
+    Ok(latest.map(|(_, _, id, path)| (id, path)))
 }
 
-/*
+/**
     Project is the representation of the CoMPASS project directory. It recieves Notify::Events when a directory/file
     is created/updated, and then retrieves the relevant data and sends it off to the server through the data sender channel.
+    The start and end of each run are sent as RunStart and RunStop messages, with the CoMPASS settings of the run
+    sent as a Metadata message right after the RunStart. A run ends when a new run directory appears,
+    a stop file is written to the run directory, the run directory is removed, or its files stop growing for run_timeout.
+    Once a run has ended it is no longer read, except that a run ended by the timeout is resumed (with a new RunStart)
+    if its files grow again before another run starts, as happens after a long pause in the beam.
+    The whole project directory is watched recursively, as run directories may be nested below it;
+    events for paths outside of the run being followed are ignored.
  */
 #[derive(Debug)]
 pub struct Project {
     project_path: PathBuf,
+    options: RunOptions,
+    run_pattern: RunPattern,
+    rescan_interval: Duration,
+    tail_interval: Duration,
+    read_mode: ReadMode,
+    run_timeout: Option<Duration>,
+    run_stop_files: Vec<String>,
     active_run: Option<ActiveRun>,
+    timed_out_run: Option<ActiveRun>,
     event_queue: Receiver<Event>,
     data_queue: Sender<Bytes>
 }
 
 impl Project {
 
-    /*
-        Project needs the project path, a reciever channel for Notify::Events, and sender channel for binary data from
-        the CoMPASS data files.
+    /**
+        Project needs the config (for the project path and data options), a reciever channel for Notify::Events,
+        and sender channel for binary data from the CoMPASS data files.
      */
-    pub fn new(path: &Path, event: Receiver<Event>, data: Sender<Bytes>) -> Result<Self, ProjectError> {
+    pub fn new(config: &Config, event: Receiver<Event>, data: Sender<Bytes>) -> Result<Self, ProjectError> {
+        let path = &config.project_directory;
         if !path.exists() {
             return Err(ProjectError::ProjectDirError);
         }
 
-        let proj = Project { project_path: path.to_path_buf(), active_run: None, event_queue: event, data_queue: data };
+        let mut proj = Project {
+            project_path: path.to_path_buf(),
+            options: RunOptions::new(config),
+            run_pattern: config.run_pattern.clone(),
+            rescan_interval: Duration::from_millis(config.rescan_interval_ms.max(1)),
+            tail_interval: Duration::from_millis(config.tail_interval_ms.max(1)),
+            read_mode: config.read_mode,
+            run_timeout: config.run_timeout_ms.map(Duration::from_millis),
+            run_stop_files: config.run_stop_files.clone(),
+            active_run: None,
+            timed_out_run: None,
+            event_queue: event,
+            data_queue: data
+        };
 
         tracing::trace!("Hooked to project directory: {}", proj.project_path.display());
-        return Ok(proj);
+        proj.attach_to_latest_run(config.attach_mode)?;
+        Ok(proj)
     }
 
     /*
-        The event handling loop. The main task which should be spawned for Project
+        If ritual is started while a run is in progress, no run directory creation event will arrive
+        for it. Make the most recent run in the project the active run, either reading its files from
+        the beginning or skipping to their current end.
+     */
+    fn attach_to_latest_run(&mut self, mode: AttachMode) -> Result<(), ProjectError> {
+        if mode == AttachMode::Off {
+            return Ok(());
+        }
+
+        let (id, run_dir) = match find_latest_run_dir(&self.project_path, &self.run_pattern)? {
+            Some(latest) => latest,
+            None => return Ok(())
+        };
+
+        let mut run = ActiveRun::new(id, &run_dir, &self.options)?;
+        if mode == AttachMode::End {
+            run.skip_to_end();
+        }
+        tracing::info!("Attached to existing run: {}", run.directory().display());
+        self.active_run = Some(run);
+        Ok(())
+    }
+
+    /**
+        The event handling loop. The main task which should be spawned for Project.
+        Alongside Notify::Events, the active run is periodically rescanned for files which appeared
+        without an event (or whose header was not written yet when the event arrived), and periodically
+        tailed, so that data is still read if the platform reports writes in a way we don't expect (or not at all).
      */
     pub async fn handle_events(&mut self) -> Result<(), ProjectError> {
+        let mut rescan_timer = tokio::time::interval(self.rescan_interval);
+        rescan_timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let mut tail_timer = tokio::time::interval(self.tail_interval);
+        tail_timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        //A run attached to at startup has started as far as clients are concerned
+        if let Some(run) = self.active_run.as_ref() {
+            self.announce_run(run).await;
+        }
         loop {
-            tracing::trace!("Started running!");
-            match self.event_queue.recv().await {
-                Some(event) => {
-                    match &event.kind {
-                        EventKind::Create(kind) => {
-                            match kind {
-                                CreateKind::Folder => { //Only care about directories being created
-                                    self.handle_create_dir(&event)
-                                }
-                                _ => {}
-                            }
-                        },
-                        EventKind::Modify(kind) => {
-                            match kind {
-                                ModifyKind::Any => { //I suspect that this will be an issue... doesn't seem specific enough
-                                    tracing::trace!("Here!");
-                                    self.handle_modify_file(&event).await
-                                }
-                                _ => {}
-                            }
-                        },
-                        _ => { tracing::trace!("Something else!")}
+            tokio::select! {
+                event = self.event_queue.recv() => {
+                    match event {
+                        Some(event) => self.handle_event(&event).await,
+                        None => {
+                            tracing::info!("Notify event queue is shutdown");
+                            return Ok(())
+                        }
                     }
-                },
-                None => {
-                    tracing::info!("Notify event queue is shutdown");
-                    return Ok(())
                 }
-            };
-            tracing::trace!("I made it through!")
+                _ = rescan_timer.tick() => self.handle_rescan(),
+                _ = tail_timer.tick() => self.handle_tail().await
+            }
+        }
+    }
+
+    /*
+        Dispatch a Notify::Event. Native watchers report what kind of path was created and how a file was
+        modified, but the polling watcher only reports Create(Any) and metadata/data modifications, so both
+        forms are handled the same way. Which modify kind a write shows up as varies by platform (inotify reports
+        Data, others Any), so any modification or a close after writing is treated as new data.
+     */
+    async fn handle_event(&mut self, event: &Event) {
+        match &event.kind {
+            EventKind::Create(CreateKind::Folder) => {
+                self.handle_create_dir(event).await
+            },
+            EventKind::Create(CreateKind::File) => {
+                self.handle_create_file(event).await
+            },
+            EventKind::Create(_) => {
+                if event.paths.iter().any(|path| path.is_dir()) {
+                    self.handle_create_dir(event).await
+                } else {
+                    self.handle_create_file(event).await
+                }
+            },
+            EventKind::Remove(_) => {
+                self.handle_remove(event).await
+            },
+            EventKind::Modify(ModifyKind::Name(_)) => { tracing::trace!("Rename occurred!") },
+            EventKind::Modify(_) | EventKind::Access(AccessKind::Close(AccessMode::Write)) => {
+                self.handle_modify_file(event).await
+            },
+            _ => { tracing::trace!("Something else!")}
         }
     }
 
@@ -131,110 +227,523 @@ impl Project {
         When a directory is created, check that it is a run directory,
         and if it is shift the active run to this directory. The creation
         of a new run directory should signal the start of a new run.
+        Any other directory may be a data directory of the active run, so rescan.
      */
-    fn handle_create_dir(&mut self, event: &Event) {
+    async fn handle_create_dir(&mut self, event: &Event) {
 
         tracing::trace!("Create dir occurred!");
-        if event.paths.len() == 0 {
+        if event.paths.is_empty() {
             tracing::trace!("Create with no paths occured!");
             return;
         }
         
         for path in event.paths.iter() {
-            if is_run_dir(path) {
-                self.active_run = match ActiveRun::new(path) {
-                    Ok(ar) => Some(ar),
+            if let Some(id) = self.run_pattern.parse(path) {
+                if self.active_run.is_some() {
+                    self.read_active_run("Project::handle_create_dir").await;
+                    self.stop_active_run(SystemTime::now()).await;
+                }
+                match ActiveRun::new(id, path, &self.options) {
+                    Ok(ar) => self.start_run(ar).await,
                     Err(e) => {
                         tracing::error!("Found a dir that looks like a run, but couldn't be inited at Project::handle_create_dir! Error: {}", e);
-                        return
                     }
                 };
                 return;
             }
         }
+
+        self.handle_rescan();
+    }
+
+    /*
+        When a file is created, start reading it if it is a CoMPASS binary in the active run.
+        A stop file in the run directory ends the run, after the remaining data is read.
+     */
+    async fn handle_create_file(&mut self, event: &Event) {
+        tracing::trace!("Create file occurred!");
+        let run = match self.active_run.as_mut() {
+            Some(run) => run,
+            None => return
+        };
+        for path in event.paths.iter().filter(|p| is_compass_binary(p)) {
+            run.add_file(path);
+        }
+
+        let is_stop_file = |path: &PathBuf| {
+            path.parent() == Some(run.directory()) &&
+            path.file_name().is_some_and(|name| self.run_stop_files.iter().any(|stop| name == stop.as_str()))
+        };
+        if event.paths.iter().any(is_stop_file) {
+            tracing::info!("Run stop file written to {}", run.directory().display());
+            self.read_active_run("Project::handle_create_file").await;
+            self.stop_active_run(SystemTime::now()).await;
+        }
+    }
+
+    //If the active run directory (or the directory containing it) is removed, the run is over
+    async fn handle_remove(&mut self, event: &Event) {
+        tracing::trace!("Remove occurred!");
+        let is_removed = |run: &ActiveRun| event.paths.iter().any(|path| run.directory().starts_with(path));
+        if self.timed_out_run.as_ref().is_some_and(is_removed) {
+            self.timed_out_run = None;
+        }
+        let removed = self.active_run.as_ref().is_some_and(is_removed);
+        if removed {
+            tracing::info!("Active run directory was removed");
+            self.stop_active_run(SystemTime::now()).await;
+        }
+    }
+
+    //Read the active run and end it if its files have stopped growing, or resume the run ended by the timeout if they grow again
+    async fn handle_tail(&mut self) {
+        self.resume_timed_out_run().await;
+        self.read_active_run("Project::handle_tail").await;
+        let timeout = match self.run_timeout {
+            Some(t) => t,
+            None => return
+        };
+        let stop_time = match self.active_run.as_ref() {
+            Some(run) if run.idle_time() >= timeout => run.last_activity(),
+            _ => return
+        };
+        tracing::info!("No new data for {} ms, ending the active run", timeout.as_millis());
+        self.timed_out_run = self.stop_active_run(stop_time).await;
+    }
+
+    //Make the run ended by the timeout active again if its files have grown since
+    async fn resume_timed_out_run(&mut self) {
+        let (data, grew) = match self.timed_out_run.as_mut() {
+            Some(run) => {
+                let idle_since = run.last_activity();
+                if let Err(e) = run.rescan() {
+                    tracing::error!("Error rescanning the timed out run at Project::resume_timed_out_run: {}", e);
+                }
+                let data = run.read_data_from_all_files();
+                (data, run.last_activity() != idle_since)
+            },
+            None => return
+        };
+        if !grew {
+            return;
+        }
+        if let Some(run) = self.timed_out_run.take() {
+            tracing::info!("New data after the run timed out, resuming the run");
+            self.start_run(run).await;
+            self.send_data(data, "Project::resume_timed_out_run").await;
+        }
+    }
+
+    fn handle_rescan(&mut self) {
+        if let Some(run) = self.active_run.as_mut() {
+            if let Err(e) = run.rescan() {
+                tracing::error!("Error rescanning the active run at Project::handle_rescan: {}", e);
+            }
+        }
     }
 
     /*
         When a file is modified, check that it is a CoMPASS binary
         data file, by chekcing the extension. If it is a CoMPASS binary, 
-        read all available new data from *every* file in the run. 
+        read the new data from that file only. In batch mode reading is left to the tail timer.
+        Without an active run, a write may resume the run which timed out.
      */
     async fn handle_modify_file(&mut self, event: &Event) {
         tracing::trace!("Modify file occurred!");
-
-        if event.paths.len() == 0 {
-            tracing::trace!("Modify event with no paths occured!");
+        if self.read_mode == ReadMode::Batch {
             return;
         }
-        else if self.active_run.is_none() {
+
+        let run = match self.active_run.as_mut() {
+            Some(run) => run,
+            None => return self.resume_timed_out_run().await
+        };
+        let data: Vec<Bytes> = event.paths.iter()
+            .filter(|path| is_compass_binary(path))
+            .map(|path| run.read_data_from_file(path))
+            .collect();
+        for bytes in data {
+            self.send_data(bytes, "Project::handle_modify_file").await;
+        }
+    }
+
+    //Read any new data in the active run and send it off
+    async fn read_active_run(&mut self, context: &str) {
+        if let Some(data) = self.active_run.as_mut().map(|run| run.read_data_from_all_files()) {
+            self.send_data(data, context).await;
+        }
+    }
+
+    //Send data to the server, if there is any
+    async fn send_data(&self, data: Bytes, context: &str) {
+        if data.is_empty() {
             return;
         }
+        match self.data_queue.send(data).await {
+            Ok(_) => {},
+            Err(e) => tracing::error!("Error on sending data from {}: {}", context, e)
+        };
+    }
 
-        for path in event.paths.iter() {
-            if path.extension().unwrap() == COMPASS_BINARY_EXT {
-                let data = self.active_run.as_mut().unwrap().read_data_from_all_files();
-                match self.data_queue.send(data).await {
-                    Ok(_) => {},
-                    Err(e) => tracing::error!("Error on sending data from Project::handle_modify_file: {}", e)
-                };
+    //Make the run active and let clients know it started. A run which timed out can no longer resume.
+    async fn start_run(&mut self, run: ActiveRun) {
+        tracing::info!("Run started: {}", run.directory().display());
+        self.announce_run(&run).await;
+        self.active_run = Some(run);
+        self.timed_out_run = None;
+    }
+
+    //Send the RunStart of a run, followed by its settings if they can be found
+    async fn announce_run(&self, run: &ActiveRun) {
+        self.send_run_message(MessageKind::RunStart, run.id(), run.info(None)).await;
+
+        let path = match CompassSettings::find(run.directory(), &self.project_path) {
+            Some(p) => p,
+            None => {
+                tracing::warn!("No CoMPASS settings found for run {}", run.directory().display());
                 return;
             }
+        };
+        match CompassSettings::read(&path) {
+            Ok(settings) => {
+                let message = Message::from_settings(run.id(), &settings);
+                self.send_data(convert_messages_to_bytes(vec![message]), "Project::announce_run").await;
+            },
+            Err(e) => tracing::error!("Could not load the run settings: {}", e)
         }
+    }
 
+    /*
+        End the active run. Any hits (and events) still held back by the time ordering are sent off,
+        followed by the RunStop. Does not read the files again; callers should do so first if they can.
+        Returns the stopped run.
+     */
+    async fn stop_active_run(&mut self, stop_time: SystemTime) -> Option<ActiveRun> {
+        let mut run = self.active_run.take()?;
+        if let Some(data) = run.flush_data() {
+            self.send_data(data, "Project::stop_active_run").await;
+        }
+        tracing::info!("Run stopped: {}", run.directory().display());
+        self.send_run_message(MessageKind::RunStop, run.id(), run.info(Some(stop_time))).await;
+        Some(run)
     }
-}
 
-/*
-    ActiveRun represents the active run directory in the Project.
-    Note that the data used is actually from the UNFILTERED directory.
- */
-#[derive(Debug)]
-struct ActiveRun {
-    directory: PathBuf,
-    data_files: Vec<CompassFile>
+    async fn send_run_message(&self, kind: MessageKind, run: &RunId, info: RunInfo) {
+        let message = Message::from_run_info(kind, run, &info);
+        self.send_data(convert_messages_to_bytes(vec![message]), "Project::send_run_message").await;
+    }
 }
 
-impl ActiveRun {
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::WatchMode;
+    use crate::file::{CompassDataType, CompassHit};
+    use crate::message::convert_bytes_to_messages;
+    use crate::watcher::ProjectWatcher;
+    use notify::RecursiveMode;
+    use notify::event::RemoveKind;
+
+    fn write_run(project: &Path, name: &str, n_hits: usize) {
+        let data_dir = project.join(name).join("UNFILTERED");
+        std::fs::create_dir_all(&data_dir).unwrap();
+        let data_type = CompassDataType::ENERGY;
+        let mut bytes = data_type.bits().to_le_bytes().to_vec();
+        (0..n_hits).for_each(|_| bytes.append(&mut CompassHit::default().encode(data_type)));
+        std::fs::write(data_dir.join("DataR_CH0@V1730_1_run.BIN"), bytes).unwrap();
+    }
+
+    fn config(dir: &Path) -> Config {
+        let yaml = format!("server_address: 127.0.0.1:0\nproject_directory: {}\n", dir.display());
+        serde_yaml::from_str(&yaml).unwrap()
+    }
 
-    fn new(new_dir: &Path) -> Result<ActiveRun, ProjectError> {
+    fn project(dir: &Path, mode: AttachMode) -> Project {
+        let mut config = config(dir);
+        config.attach_mode = mode;
+        let (_, events) = tokio::sync::mpsc::channel(1);
+        let (data, _) = tokio::sync::mpsc::channel(1);
+        Project::new(&config, events, data).unwrap()
+    }
 
-        if !new_dir.exists() || !new_dir.is_dir() {
-            tracing::trace!("Run directory does not exist: {}", new_dir.display());
-            return Err(ProjectError::RunDirError);
-        }
+    fn hits_available(project: &mut Project) -> u64 {
+        let bytes = project.active_run.as_mut().unwrap().read_data_from_all_files();
+        convert_bytes_to_messages(&bytes).unwrap().iter().map(|m| m.num_hits).sum()
+    }
+
+    #[test]
+    fn finds_latest_run() {
+        let dir = tempfile::tempdir().unwrap();
+        write_run(dir.path(), "run_2", 1);
+        write_run(dir.path(), "run_10", 1);
+        write_run(dir.path(), "run_9", 1);
+        write_run(dir.path(), "run_backup_old", 1);
+        std::fs::write(dir.path().join("run_11"), "not a directory").unwrap();
+        std::fs::create_dir_all(dir.path().join("settings")).unwrap();
+        let (id, path) = find_latest_run_dir(dir.path(), &RunPattern::default()).unwrap().unwrap();
+        assert_eq!((id.number, path), (10, dir.path().join("run_10")));
+
+        let custom = RunPattern::new(r"run_(\d+)|run_backup_(?<number>\d+)").unwrap();
+        write_run(dir.path(), "run_backup_20", 1);
+        assert_eq!(find_latest_run_dir(dir.path(), &custom).unwrap().unwrap().0.name, "run_backup_20");
+
+        //Runs nested below the project directory are found, but not directories within a run
+        write_run(&dir.path().join("2026").join("beam"), "run_30", 1);
+        write_run(&dir.path().join("run_10"), "run_40", 1);
+        let (id, path) = find_latest_run_dir(dir.path(), &RunPattern::default()).unwrap().unwrap();
+        assert_eq!((id.number, path), (30, dir.path().join("2026").join("beam").join("run_30")));
+    }
+
+    #[test]
+    fn attaches_at_beginning_or_end() {
+        let dir = tempfile::tempdir().unwrap();
+        write_run(dir.path(), "run_1", 5);
+
+        let mut from_beginning = project(dir.path(), AttachMode::Beginning);
+        assert_eq!(hits_available(&mut from_beginning), 5);
+
+        let mut from_end = project(dir.path(), AttachMode::End);
+        assert_eq!(hits_available(&mut from_end), 0);
+
+        assert!(project(dir.path(), AttachMode::Off).active_run.is_none());
+    }
 
-        //Latch to data in UNFILTERED
-        let data_directory = new_dir.join("/UNFILTERED/");
-        if !data_directory.exists() {
-            tracing::trace!("Data directory does not exist: {}", data_directory.display());
-            return Err(ProjectError::RunDirError);
+    //Spawn the event loop for a project tailing its files every tail_interval_ms
+    fn spawn_project(dir: &Path, tail_interval_ms: u64, read_mode: ReadMode) -> (Sender<Event>, Receiver<Bytes>) {
+        let mut config = config(dir);
+        config.tail_interval_ms = tail_interval_ms;
+        config.read_mode = read_mode;
+        spawn_with_config(config)
+    }
+
+    fn spawn_with_config(config: Config) -> (Sender<Event>, Receiver<Bytes>) {
+        let (event_tx, event_rx) = tokio::sync::mpsc::channel(8);
+        let (data_tx, data_rx) = tokio::sync::mpsc::channel(8);
+        let mut project = Project::new(&config, event_rx, data_tx).unwrap();
+        tokio::spawn(async move { project.handle_events().await });
+        (event_tx, data_rx)
+    }
+
+    fn append_hits(path: &Path, n_hits: usize) {
+        use std::io::Write;
+        let mut file = std::fs::OpenOptions::new().append(true).open(path).unwrap();
+        (0..n_hits).for_each(|_| file.write_all(&CompassHit::default().encode(CompassDataType::ENERGY)).unwrap());
+    }
+
+    async fn next_messages(data: &mut Receiver<Bytes>) -> Vec<Message> {
+        let bytes = tokio::time::timeout(Duration::from_secs(2), data.recv()).await.unwrap().unwrap();
+        convert_bytes_to_messages(&bytes).unwrap()
+    }
+
+    //Number of hits in the next data sent, skipping run control messages
+    async fn next_hits(data: &mut Receiver<Bytes>) -> u64 {
+        loop {
+            let messages = next_messages(data).await;
+            if messages.iter().any(|m| m.kind == MessageKind::Hits) {
+                return messages.iter().map(|m| m.num_hits).sum();
+            }
         }
+    }
 
-        let mut current_run = ActiveRun { directory: data_directory.to_path_buf(), data_files: vec![] };
+    //The next message of the given kind, skipping everything else
+    async fn next_of_kind(data: &mut Receiver<Bytes>, kind: MessageKind) -> Message {
+        loop {
+            if let Some(mess) = next_messages(data).await.into_iter().find(|m| m.kind == kind) {
+                return mess;
+            }
+        }
+    }
 
-        for item in new_dir.read_dir()? {
-            let filepath = &item?.path();
-            if filepath.extension().unwrap() == COMPASS_BINARY_EXT {
-                current_run.data_files.push(CompassFile::new(filepath)?);
+    //The next run control message, which must be of the given kind
+    async fn next_run_info(data: &mut Receiver<Bytes>, kind: MessageKind) -> RunInfo {
+        loop {
+            let messages = next_messages(data).await;
+            if let Some(mess) = messages.iter().find(|m| m.kind == MessageKind::RunStart || m.kind == MessageKind::RunStop) {
+                assert_eq!(mess.kind, kind);
+                return RunInfo::decode(&mess.data).unwrap();
             }
         }
+    }
 
-        tracing::trace!("Reading data in run directory: {}", current_run.directory.display());
+    #[tokio::test]
+    async fn tails_appends_without_events() {
+        let dir = tempfile::tempdir().unwrap();
+        write_run(dir.path(), "run_1", 2);
+        let file = dir.path().join("run_1").join("UNFILTERED").join("DataR_CH0@V1730_1_run.BIN");
+        let (_events, mut data) = spawn_project(dir.path(), 10, ReadMode::Changed);
+        assert_eq!(next_hits(&mut data).await, 2);
+
+        append_hits(&file, 3);
+        assert_eq!(next_hits(&mut data).await, 3);
+        append_hits(&file, 1);
+        assert_eq!(next_hits(&mut data).await, 1);
+    }
 
-        return Ok(current_run);
+    #[tokio::test]
+    async fn reads_on_write_events() {
+        let dir = tempfile::tempdir().unwrap();
+        write_run(dir.path(), "run_1", 0);
+        let file = dir.path().join("run_1").join("UNFILTERED").join("DataR_CH0@V1730_1_run.BIN");
+        //Long enough that only the first tick happens during the test
+        let (events, mut data) = spawn_project(dir.path(), 60_000, ReadMode::Changed);
+
+        for kind in [EventKind::Modify(ModifyKind::Data(notify::event::DataChange::Content)),
+                     EventKind::Modify(ModifyKind::Any),
+                     EventKind::Access(AccessKind::Close(AccessMode::Write))] {
+            append_hits(&file, 2);
+            events.send(Event::new(kind).add_path(file.clone())).await.unwrap();
+            assert_eq!(next_hits(&mut data).await, 2);
+        }
     }
 
-    //Get messages from files and convert to Bytes
-    fn read_data_from_all_files(&mut self) -> Bytes {
-        let mut messages: Vec<Message> = vec![];
+    #[tokio::test]
+    async fn reads_only_changed_file_or_batches() {
+        let dir = tempfile::tempdir().unwrap();
+        write_run(dir.path(), "run_1", 0);
+        let data_dir = dir.path().join("run_1").join("UNFILTERED");
+        let first = data_dir.join("DataR_CH0@V1730_1_run.BIN");
+        let second = data_dir.join("DataR_CH1@V1730_1_run.BIN");
+        std::fs::copy(&first, &second).unwrap();
+        let modified = |path: &Path| Event::new(EventKind::Modify(ModifyKind::Any)).add_path(path.to_path_buf());
+
+        let (events, mut data) = spawn_project(dir.path(), 60_000, ReadMode::Changed);
+        //Let the first tick of the tail timer pass before writing
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        append_hits(&first, 1);
+        append_hits(&second, 2);
+        events.send(modified(&second)).await.unwrap();
+        assert_eq!(next_hits(&mut data).await, 2);
+        events.send(modified(&first)).await.unwrap();
+        assert_eq!(next_hits(&mut data).await, 1);
+
+        let (events, mut data) = spawn_project(dir.path(), 100, ReadMode::Batch);
+        assert_eq!(next_hits(&mut data).await, 3);
+        append_hits(&first, 1);
+        append_hits(&second, 1);
+        events.send(modified(&first)).await.unwrap();
+        assert_eq!(next_hits(&mut data).await, 2);
+    }
 
-        for handle in self.data_files.iter_mut() {
-            match handle.read_data() {
-                Ok(mess) => messages.push(mess),
-                Err(e) => tracing::error!("An error occurred reading file data: {}", e)
-            }
+    #[tokio::test]
+    async fn ends_idle_runs() {
+        let dir = tempfile::tempdir().unwrap();
+        write_run(dir.path(), "run_3", 2);
+        let mut config = config(dir.path());
+        config.tail_interval_ms = 10;
+        config.run_timeout_ms = Some(100);
+        let (_events, mut data) = spawn_with_config(config);
+
+        let start = next_run_info(&mut data, MessageKind::RunStart).await;
+        assert_eq!((start.run_number, start.stop_time, start.total_hits), (3, None, 0));
+        assert_eq!(next_hits(&mut data).await, 2);
+        let stop = next_run_info(&mut data, MessageKind::RunStop).await;
+        assert_eq!(stop.total_hits, 2);
+        assert!(stop.stop_time.unwrap() >= start.start_time);
+    }
+
+    #[tokio::test]
+    async fn resumes_runs_after_the_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        write_run(dir.path(), "run_3", 2);
+        let file = dir.path().join("run_3").join("UNFILTERED").join("DataR_CH0@V1730_1_run.BIN");
+        let mut config = config(dir.path());
+        config.tail_interval_ms = 10;
+        config.run_timeout_ms = Some(100);
+        let (events, mut data) = spawn_with_config(config);
+        next_run_info(&mut data, MessageKind::RunStart).await;
+        assert_eq!(next_run_info(&mut data, MessageKind::RunStop).await.total_hits, 2);
+
+        //Writing to the run after it timed out picks it back up
+        append_hits(&file, 3);
+        let start = next_run_info(&mut data, MessageKind::RunStart).await;
+        assert_eq!((start.run_number, start.stop_time, start.total_hits), (3, None, 5));
+        assert_eq!(next_hits(&mut data).await, 3);
+        let stop = next_run_info(&mut data, MessageKind::RunStop).await;
+        assert_eq!((stop.run_number, stop.total_hits), (3, 5));
+
+        //Once a new run has started, the old one stays stopped
+        std::fs::create_dir_all(dir.path().join("run_4")).unwrap();
+        events.send(Event::new(EventKind::Create(CreateKind::Folder)).add_path(dir.path().join("run_4"))).await.unwrap();
+        assert_eq!(next_run_info(&mut data, MessageKind::RunStart).await.run_number, 4);
+        append_hits(&file, 1);
+        assert_eq!(next_run_info(&mut data, MessageKind::RunStop).await.run_number, 4);
+    }
+
+    #[tokio::test]
+    async fn follows_run_lifecycle() {
+        let dir = tempfile::tempdir().unwrap();
+        write_run(dir.path(), "run_1", 0);
+        let file = dir.path().join("run_1").join("UNFILTERED").join("DataR_CH0@V1730_1_run.BIN");
+        let mut config = config(dir.path());
+        config.tail_interval_ms = 60_000;
+        config.run_stop_files = vec![String::from("run.info")];
+        let (events, mut data) = spawn_with_config(config);
+        assert_eq!(next_run_info(&mut data, MessageKind::RunStart).await.run_number, 1);
+
+        //Stop file, with data written just before it
+        append_hits(&file, 2);
+        std::fs::write(dir.path().join("run_1").join("run.info"), "").unwrap();
+        events.send(Event::new(EventKind::Create(CreateKind::File)).add_path(dir.path().join("run_1").join("run.info"))).await.unwrap();
+        assert_eq!(next_hits(&mut data).await, 2);
+        assert_eq!(next_run_info(&mut data, MessageKind::RunStop).await.total_hits, 2);
+
+        //New run directories; others are ignored
+        for name in ["run_2", "my_run_notes", "run_3"] {
+            std::fs::create_dir_all(dir.path().join(name)).unwrap();
+            events.send(Event::new(EventKind::Create(CreateKind::Folder)).add_path(dir.path().join(name))).await.unwrap();
         }
+        assert_eq!(next_run_info(&mut data, MessageKind::RunStart).await.run_number, 2);
+        assert_eq!(next_run_info(&mut data, MessageKind::RunStop).await.run_number, 2);
+        assert_eq!(next_run_info(&mut data, MessageKind::RunStart).await.run_number, 3);
+
+        //Run directory removed
+        std::fs::remove_dir_all(dir.path().join("run_3")).unwrap();
+        events.send(Event::new(EventKind::Remove(RemoveKind::Folder)).add_path(dir.path().join("run_3"))).await.unwrap();
+        let stop = next_run_info(&mut data, MessageKind::RunStop).await;
+        assert_eq!((stop.run_number, stop.total_hits), (3, 0));
+    }
 
-        convert_messages_to_bytes(messages)
+    #[tokio::test(flavor = "multi_thread")]
+    async fn follows_nested_runs_through_the_watcher() {
+        let dir = tempfile::tempdir().unwrap();
+        let (event_tx, event_rx) = tokio::sync::mpsc::channel(5);
+        let (data_tx, mut data) = tokio::sync::mpsc::channel(8);
+        let mut config = config(dir.path());
+        config.rescan_interval_ms = 20;
+        config.tail_interval_ms = 20;
+        let mut project = Project::new(&config, event_rx, data_tx).unwrap();
+        let mut watcher = ProjectWatcher::new(event_tx, WatchMode::Native, Duration::from_secs(1)).unwrap();
+        watcher.watch(dir.path(), RecursiveMode::Recursive).unwrap();
+        tokio::spawn(async move { project.handle_events().await });
+
+        //The subdirectory has to be watched before the run is created within it
+        std::fs::create_dir_all(dir.path().join("2026")).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        std::fs::create_dir_all(dir.path().join("2026").join("run_1")).unwrap();
+        assert_eq!(next_run_info(&mut data, MessageKind::RunStart).await.run_number, 1);
+        write_run(&dir.path().join("2026"), "run_1", 3);
+        assert_eq!(next_hits(&mut data).await, 3);
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn sends_run_settings() {
+        let dir = tempfile::tempdir().unwrap();
+        write_run(dir.path(), "run_1", 0);
+        std::fs::write(dir.path().join("settings.xml"), "<configuration><board><modelName>V1730</modelName></board></configuration>").unwrap();
+        let (events, mut data) = spawn_project(dir.path(), 60_000, ReadMode::Changed);
+
+        let metadata = next_of_kind(&mut data, MessageKind::Metadata).await;
+        assert_eq!(metadata.source.file, "run_1");
+        let settings: serde_json::Value = serde_json::from_slice(&metadata.data).unwrap();
+        assert_eq!(settings["boards"][0]["model"], "V1730");
+
+        //Settings copied into the run directory take precedence
+        std::fs::create_dir_all(dir.path().join("run_2")).unwrap();
+        std::fs::write(dir.path().join("run_2").join("settings.xml"), "<configuration><board><modelName>V1725</modelName></board></configuration>").unwrap();
+        events.send(Event::new(EventKind::Create(CreateKind::Folder)).add_path(dir.path().join("run_2"))).await.unwrap();
+        let metadata = next_of_kind(&mut data, MessageKind::Metadata).await;
+        assert_eq!(metadata.source.file, "run_2");
+        let settings: serde_json::Value = serde_json::from_slice(&metadata.data).unwrap();
+        assert_eq!(settings["boards"][0]["model"], "V1725");
+    }
+}
@@ -0,0 +1,319 @@
+use bytes::Bytes;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+use tokio::sync::mpsc::Sender;
+
+use crate::config::Config;
+use crate::file::{CompassFileError, CompassHit};
+use crate::message::{Message, MessageKind, convert_messages_to_bytes};
+use crate::project::ProjectError;
+use crate::run::{ActiveRun, RunInfo, RunOptions, unix_time_ms};
+use crate::settings::CompassSettings;
+
+//Most hits sent at once
+const MAX_BATCH_HITS: usize = 10_000;
+
+//Wall clock time covered by each batch when replaying in real time (or a multiple of it)
+const BATCH_DURATION: Duration = Duration::from_millis(10);
+
+#[derive(Debug)]
+pub enum ReplayError {
+    RunDirError(PathBuf),
+    FileError(CompassFileError),
+    RunError(ProjectError),
+    SendError
+}
+
+impl From<CompassFileError> for ReplayError {
+    fn from(value: CompassFileError) -> Self {
+        ReplayError::FileError(value)
+    }
+}
+
+impl From<ProjectError> for ReplayError {
+    fn from(value: ProjectError) -> Self {
+        ReplayError::RunError(value)
+    }
+}
+
+impl std::fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::RunDirError(p) => write!(f, "{} is not a run directory matching the run pattern", p.display()),
+            Self::FileError(e) => write!(f, "Replay encountered an error with the run files: {}", e),
+            Self::RunError(e) => write!(f, "Replay could not read the run: {}", e),
+            Self::SendError => write!(f, "Replay could not send data, the server is shutdown")
+        }
+    }
+}
+
+impl std::error::Error for ReplayError {
+
+}
+
+/**
+    How fast a run is replayed. Max sends the data as fast as clients take it. Scaled follows the hit timestamps,
+    with 1.0 being real time, 2.0 twice as fast, etc. Parsed from "max", "realtime", or a factor such as "2" or "2x".
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReplaySpeed {
+    Max,
+    Scaled(f64)
+}
+
+impl FromStr for ReplaySpeed {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "max" => Ok(ReplaySpeed::Max),
+            "realtime" => Ok(ReplaySpeed::Scaled(1.0)),
+            _ => match s.trim_end_matches('x').parse::<f64>() {
+                Ok(factor) if factor > 0.0 && factor.is_finite() => Ok(ReplaySpeed::Scaled(factor)),
+                _ => Err(format!("Invalid replay speed {}; expected max, realtime, or a positive factor like 2x", s))
+            }
+        }
+    }
+}
+
+/**
+    Replay streams a finished run through the server as if it were live. Hits of all files are merged
+    in timestamp order and sent in batches, paced by the timestamps unless replaying at Max speed.
+    The hits are read from, and fed back through, an ActiveRun of the run directory, so the messages are the same
+    as for a live run: RunStart, Metadata, the data (per file or time ordered, with events if configured), and RunStop.
+ */
+#[derive(Debug)]
+pub struct Replay {
+    run: ActiveRun,
+    speed: ReplaySpeed,
+    next_hits: Vec<Option<CompassHit>> //Next hit of each data file of the run
+}
+
+impl Replay {
+
+    pub fn new(run_dir: &Path, config: &Config, speed: ReplaySpeed) -> Result<Self, ReplayError> {
+        let id = match config.run_pattern.parse(run_dir) {
+            Some(id) if run_dir.is_dir() => id,
+            _ => return Err(ReplayError::RunDirError(run_dir.to_path_buf()))
+        };
+        let run = ActiveRun::new(id, run_dir, &RunOptions::new(config))?;
+        Ok(Replay { run, speed, next_hits: vec![] })
+    }
+
+    ///Replay the whole run, sending the data to the server
+    pub async fn run(&mut self, data_queue: &Sender<Bytes>) -> Result<(), ReplayError> {
+        let start_time = unix_time_ms(SystemTime::now());
+        let directory = self.run.directory().to_path_buf();
+        let n_files = self.run.data_files().len();
+        tracing::info!("Replaying run {} from {} files", directory.display(), n_files);
+
+        let id = self.run.id().clone();
+        let info = RunInfo { start_time, ..self.run.info(None) };
+        let mut messages = vec![Message::from_run_info(MessageKind::RunStart, &id, &info)];
+        let project_dir = directory.parent().unwrap_or(&directory);
+        if let Some(path) = CompassSettings::find(&directory, project_dir) {
+            match CompassSettings::read(&path) {
+                Ok(settings) => messages.push(Message::from_settings(&id, &settings)),
+                Err(e) => tracing::error!("Could not load the run settings: {}", e)
+            }
+        }
+        send(data_queue, messages).await?;
+
+        //Merge the files by their next hit
+        let mut queue = BinaryHeap::new();
+        self.next_hits = vec![];
+        for file in 0..n_files {
+            let next = self.run.read_hit(file)?;
+            if let Some(hit) = next.as_ref() {
+                queue.push(Reverse((hit.timestamp, file)));
+            }
+            self.next_hits.push(next);
+        }
+
+        let clock_start = tokio::time::Instant::now();
+        let mut first_timestamp: Option<u64> = None;
+        loop {
+            let batch = self.next_batch(&mut queue)?;
+            let last_timestamp = match batch.last() {
+                Some((_, hit)) => hit.timestamp,
+                None => break
+            };
+            if let ReplaySpeed::Scaled(factor) = self.speed {
+                let first = *first_timestamp.get_or_insert(batch[0].1.timestamp);
+                //Timestamps are in ps
+                let elapsed = Duration::from_secs_f64(last_timestamp.saturating_sub(first) as f64 * 1.0e-12 / factor);
+                tokio::time::sleep_until(clock_start + elapsed).await;
+            }
+            send_bytes(data_queue, self.run.push_hits(self.group_by_file(batch))).await?;
+        }
+
+        if let Some(bytes) = self.run.flush_data() {
+            send_bytes(data_queue, bytes).await?;
+        }
+        let info = RunInfo { start_time, ..self.run.info(Some(SystemTime::now())) };
+        send(data_queue, vec![Message::from_run_info(MessageKind::RunStop, &id, &info)]).await?;
+
+        tracing::info!("Finished replaying run {}, {} hits", directory.display(), info.total_hits);
+        Ok(())
+    }
+
+    /*
+        Take the next hits in timestamp order, as (file, hit). A batch covers BATCH_DURATION of
+        replay time, or is MAX_BATCH_HITS long when replaying at Max speed.
+     */
+    fn next_batch(&mut self, queue: &mut BinaryHeap<Reverse<(u64, usize)>>) -> Result<Vec<(usize, CompassHit)>, ReplayError> {
+        let span = match self.speed {
+            ReplaySpeed::Max => u64::MAX,
+            ReplaySpeed::Scaled(factor) => (BATCH_DURATION.as_secs_f64() * factor * 1.0e12) as u64
+        };
+        let mut batch = vec![];
+        let mut batch_start: Option<u64> = None;
+        while let Some(Reverse((timestamp, index))) = queue.peek().copied() {
+            let start = *batch_start.get_or_insert(timestamp);
+            if batch.len() == MAX_BATCH_HITS || timestamp.saturating_sub(start) > span {
+                break;
+            }
+            queue.pop();
+            if let Some(hit) = self.next_hits[index].take() {
+                batch.push((index, hit));
+            }
+            let next = self.run.read_hit(index)?;
+            if let Some(hit) = next.as_ref() {
+                queue.push(Reverse((hit.timestamp, index)));
+            }
+            self.next_hits[index] = next;
+        }
+        Ok(batch)
+    }
+
+    //Split a batch into the hits of each file, in file order, as the run takes them
+    fn group_by_file(&self, batch: Vec<(usize, CompassHit)>) -> Vec<(PathBuf, Vec<CompassHit>)> {
+        let paths = self.run.data_files();
+        let mut files: Vec<Vec<CompassHit>> = vec![vec![]; paths.len()];
+        batch.into_iter().for_each(|(file, hit)| files[file].push(hit));
+        paths.into_iter()
+            .zip(files)
+            .filter(|(_, hits)| !hits.is_empty())
+            .map(|(path, hits)| (path.to_path_buf(), hits))
+            .collect()
+    }
+}
+
+async fn send(data_queue: &Sender<Bytes>, messages: Vec<Message>) -> Result<(), ReplayError> {
+    send_bytes(data_queue, convert_messages_to_bytes(messages)).await
+}
+
+async fn send_bytes(data_queue: &Sender<Bytes>, bytes: Bytes) -> Result<(), ReplayError> {
+    match data_queue.send(bytes).await {
+        Ok(_) => Ok(()),
+        Err(_) => Err(ReplayError::SendError)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file::CompassDataType;
+    use crate::message::convert_bytes_to_messages;
+
+    fn write_file(path: &Path, hits: &[CompassHit]) {
+        let data_type = CompassDataType::ENERGY;
+        let mut bytes = data_type.bits().to_le_bytes().to_vec();
+        hits.iter().for_each(|hit| bytes.append(&mut hit.encode(data_type)));
+        std::fs::write(path, bytes).unwrap();
+    }
+
+    fn hit(channel: u16, timestamp: u64) -> CompassHit {
+        CompassHit { channel, timestamp, ..Default::default() }
+    }
+
+    //A run with two channels whose hits interleave in time. Timestamps are in ps.
+    fn write_run(project: &Path) -> PathBuf {
+        let data_dir = project.join("run_5").join("UNFILTERED");
+        std::fs::create_dir_all(&data_dir).unwrap();
+        write_file(&data_dir.join("DataR_CH0@V1730_1_run_5.BIN"), &[hit(0, 0), hit(0, 20_000_000_000), hit(0, 100_000_000_000)]);
+        write_file(&data_dir.join("DataR_CH1@V1730_1_run_5.BIN"), &[hit(1, 10_000_000_000), hit(1, 30_000_000_000)]);
+        project.join("run_5")
+    }
+
+    fn config(time_ordered: bool) -> Config {
+        let yaml = format!("server_address: 127.0.0.1:0\nproject_directory: .\ntime_ordered: {}\n", time_ordered);
+        serde_yaml::from_str(&yaml).unwrap()
+    }
+
+    async fn replay(run_dir: &Path, config: &Config, speed: ReplaySpeed) -> Vec<Message> {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(64);
+        Replay::new(run_dir, config, speed).unwrap().run(&tx).await.unwrap();
+        drop(tx);
+        let mut messages = vec![];
+        while let Some(bytes) = rx.recv().await {
+            messages.append(&mut convert_bytes_to_messages(&bytes).unwrap());
+        }
+        messages
+    }
+
+    #[test]
+    fn parses_speed() {
+        assert_eq!("max".parse(), Ok(ReplaySpeed::Max));
+        assert_eq!("realtime".parse(), Ok(ReplaySpeed::Scaled(1.0)));
+        assert_eq!("2.5x".parse(), Ok(ReplaySpeed::Scaled(2.5)));
+        assert_eq!("10".parse(), Ok(ReplaySpeed::Scaled(10.0)));
+        assert!("0".parse::<ReplaySpeed>().is_err());
+        assert!("fast".parse::<ReplaySpeed>().is_err());
+    }
+
+    #[tokio::test]
+    async fn replays_run_as_fast_as_possible() {
+        let dir = tempfile::tempdir().unwrap();
+        let run_dir = write_run(dir.path());
+
+        let messages = replay(&run_dir, &config(false), ReplaySpeed::Max).await;
+        assert_eq!(messages.first().unwrap().kind, MessageKind::RunStart);
+        let stop = messages.last().unwrap();
+        assert_eq!(stop.kind, MessageKind::RunStop);
+        assert_eq!(RunInfo::decode(&stop.data).unwrap().total_hits, 5);
+        assert!(messages.iter().all(|m| m.run_number == Some(5)));
+
+        let data: Vec<&Message> = messages.iter().filter(|m| m.kind == MessageKind::Hits).collect();
+        assert_eq!(data.len(), 2);
+        assert_eq!(data[0].source.file, "DataR_CH0@V1730_1_run_5.BIN");
+        assert_eq!(data[0].source.origin, "UNFILTERED");
+        assert_eq!((data[0].num_hits, data[1].num_hits), (3, 2));
+
+        let messages = replay(&run_dir, &config(true), ReplaySpeed::Max).await;
+        //As in a live run, the last hit is held back by the time ordering until the run ends
+        let data: Vec<&Message> = messages.iter().filter(|m| m.kind == MessageKind::Hits).collect();
+        assert_eq!(data.iter().map(|m| m.num_hits).collect::<Vec<u64>>(), vec![4, 1]);
+        let channels: Vec<u16> = data.iter()
+            .flat_map(|m| CompassHit::decode_all(CompassDataType::ENERGY, &m.data))
+            .map(|h| h.channel)
+            .collect();
+        assert_eq!(channels, vec![0, 1, 0, 1, 0]);
+        assert_eq!(messages.last().unwrap().kind, MessageKind::RunStop);
+    }
+
+    #[tokio::test]
+    async fn paces_replay_by_timestamps() {
+        let dir = tempfile::tempdir().unwrap();
+        let run_dir = write_run(dir.path());
+
+        //The run spans 100 ms
+        let start = std::time::Instant::now();
+        let messages = replay(&run_dir, &config(true), ReplaySpeed::Scaled(2.0)).await;
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        let data: Vec<&Message> = messages.iter().filter(|m| m.kind == MessageKind::Hits).collect();
+        assert!(data.len() > 1);
+        assert_eq!(data.iter().map(|m| m.num_hits).sum::<u64>(), 5);
+    }
+
+    #[test]
+    fn rejects_non_run_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("not_a_run")).unwrap();
+        assert!(matches!(Replay::new(&dir.path().join("not_a_run"), &config(false), ReplaySpeed::Max), Err(ReplayError::RunDirError(_))));
+        assert!(matches!(Replay::new(&dir.path().join("run_1"), &config(false), ReplaySpeed::Max), Err(ReplayError::RunDirError(_))));
+    }
+}
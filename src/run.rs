@@ -0,0 +1,686 @@
+use bytes::Bytes;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::{Config, DataDirectory};
+use crate::event::{CompassEvent, EventBuilder, EventBuilderConfig};
+use crate::file::{CompassDataType, CompassFile, CompassFileError, CompassHit};
+use crate::merge::HitMerger;
+use crate::message::{Message, MessageSource, convert_messages_to_bytes};
+use crate::project::ProjectError;
+
+//File extension of CAEN CoMPASS binary data files
+const COMPASS_BINARY_EXT: &str = "BIN";
+
+//Check if the given Path has the extension of a CoMPASS binary data file
+pub fn is_compass_binary(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == COMPASS_BINARY_EXT)
+}
+
+//CoMPASS names run directories run_#
+const DEFAULT_RUN_PATTERN: &str = r"^run_(\d+)$";
+
+#[derive(Debug)]
+pub enum RunPatternError {
+    InvalidRegex(regex::Error),
+    NoRunNumber(String)
+}
+
+impl std::fmt::Display for RunPatternError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidRegex(e) => write!(f, "Run directory pattern is not a valid regex: {}", e),
+            Self::NoRunNumber(p) => write!(f, "Run directory pattern {} has no capture group for the run number", p)
+        }
+    }
+}
+
+impl std::error::Error for RunPatternError {
+
+}
+
+/**
+    Identifies a run: the name of its directory and the run number parsed from it
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunId {
+    pub name: String,
+    pub number: u32
+}
+
+impl std::fmt::Display for RunId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+/**
+    The pattern run directory names must follow, as a regex. The whole directory name must match, and the run
+    number is taken from the capture group named number, or the first capture group if there is no such group.
+    Names which do not match (i.e. run_backup_old or my_run_notes), or are not valid UTF-8, are not runs.
+ */
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct RunPattern {
+    regex: Regex
+}
+
+impl RunPattern {
+    pub fn new(pattern: &str) -> Result<Self, RunPatternError> {
+        let regex = match Regex::new(pattern) {
+            Ok(r) => r,
+            Err(e) => return Err(RunPatternError::InvalidRegex(e))
+        };
+        if regex.captures_len() < 2 {
+            return Err(RunPatternError::NoRunNumber(pattern.to_string()));
+        }
+        Ok(RunPattern { regex })
+    }
+
+    ///Parse a path as a run directory. This does not check if the path is a directory.
+    pub fn parse(&self, dir: &Path) -> Option<RunId> {
+        let name = dir.file_name()?.to_str()?;
+        let captures = self.regex.captures(name)?;
+        let whole = captures.get(0)?;
+        if whole.start() != 0 || whole.end() != name.len() {
+            return None;
+        }
+        let number = captures.name("number").or_else(|| captures.get(1))?.as_str().parse().ok()?;
+        Some(RunId { name: name.to_string(), number })
+    }
+}
+
+impl Default for RunPattern {
+    fn default() -> Self {
+        RunPattern { regex: Regex::new(DEFAULT_RUN_PATTERN).expect("Default run pattern is invalid") }
+    }
+}
+
+impl TryFrom<String> for RunPattern {
+    type Error = RunPatternError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        RunPattern::new(&value)
+    }
+}
+
+impl From<RunPattern> for String {
+    fn from(value: RunPattern) -> Self {
+        value.regex.as_str().to_string()
+    }
+}
+
+//Milliseconds since the unix epoch
+pub fn unix_time_ms(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+/**
+    Summary of a run, sent to clients when a run starts and stops. Times are in milliseconds since the unix epoch.
+    total_hits is the number of hits read from the files of the run (all data directories).
+ */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RunInfo {
+    pub run_number: u32,
+    pub start_time: u64,
+    pub stop_time: Option<u64>,
+    pub total_hits: u64
+}
+
+impl RunInfo {
+
+    ///Encode as run number (u32), start time (u64), stop time (u64, 0 while running), total hits (u64)
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        bytes.extend_from_slice(&self.run_number.to_le_bytes());
+        bytes.extend_from_slice(&self.start_time.to_le_bytes());
+        bytes.extend_from_slice(&self.stop_time.unwrap_or(0).to_le_bytes());
+        bytes.extend_from_slice(&self.total_hits.to_le_bytes());
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Option<RunInfo> {
+        if bytes.len() < 28 {
+            return None;
+        }
+        let stop_time = u64::from_le_bytes(bytes[12..20].try_into().ok()?);
+        Some(RunInfo {
+            run_number: u32::from_le_bytes(bytes[0..4].try_into().ok()?),
+            start_time: u64::from_le_bytes(bytes[4..12].try_into().ok()?),
+            stop_time: (stop_time != 0).then_some(stop_time),
+            total_hits: u64::from_le_bytes(bytes[20..28].try_into().ok()?)
+        })
+    }
+}
+
+/*
+    Options for how data is processed within a run, taken from the Config
+ */
+#[derive(Debug, Clone)]
+pub struct RunOptions {
+    pub data_directories: Vec<DataDirectory>,
+    pub time_ordered: bool,
+    pub hold_back_window: u64,
+    pub event_builder: Option<EventBuilderConfig>
+}
+
+impl RunOptions {
+    pub fn new(config: &Config) -> Self {
+        RunOptions {
+            data_directories: config.data_directories.clone(),
+            time_ordered: config.time_ordered,
+            hold_back_window: config.hold_back_window,
+            event_builder: config.event_builder.clone()
+        }
+    }
+
+    //Both the time ordered stream and the event builder need hits merged across files
+    fn needs_merge(&self) -> bool {
+        self.time_ordered || self.event_builder.is_some()
+    }
+}
+
+/*
+    ActiveRun represents the active run directory in the Project.
+    The data itself lives in the data subdirectories of the run (UNFILTERED, FILTERED, etc.), each of which
+    is handled separately so that the same hits are never merged (or built into events) twice.
+    CoMPASS creates the data subdirectories and files some time after the run directory, so they may not
+    exist yet when the run starts; they are added as they appear (see add_file and rescan).
+    The start time is taken from the run directory, and last_activity is when the files of the run last grew.
+ */
+#[derive(Debug)]
+pub struct ActiveRun {
+    id: RunId,
+    directory: PathBuf,
+    data: Vec<RunData>,
+    start_time: SystemTime,
+    last_activity: SystemTime
+}
+
+impl ActiveRun {
+
+    pub fn new(id: RunId, new_dir: &Path, options: &RunOptions) -> Result<ActiveRun, ProjectError> {
+
+        if !new_dir.exists() || !new_dir.is_dir() {
+            tracing::trace!("Run directory does not exist: {}", new_dir.display());
+            return Err(ProjectError::RunDirError);
+        }
+
+        let now = SystemTime::now();
+        let start_time = new_dir.metadata()
+            .and_then(|meta| meta.created().or_else(|_| meta.modified()))
+            .unwrap_or(now);
+        let mut current_run = ActiveRun { id, directory: new_dir.to_path_buf(), data: vec![], start_time, last_activity: now };
+
+        for origin in options.data_directories.iter() {
+            let mut data = RunData::new(origin, &new_dir.join(origin.name()), options);
+            data.rescan()?;
+            current_run.data.push(data);
+        }
+
+        tracing::trace!("Reading data in run directory: {}", current_run.directory.display());
+
+        Ok(current_run)
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    pub fn id(&self) -> &RunId {
+        &self.id
+    }
+
+    //Summary of the run so far. The stop time should be given once the run has ended.
+    pub fn info(&self, stop_time: Option<SystemTime>) -> RunInfo {
+        RunInfo {
+            run_number: self.id.number,
+            start_time: unix_time_ms(self.start_time),
+            stop_time: stop_time.map(unix_time_ms),
+            total_hits: self.total_hits()
+        }
+    }
+
+    pub fn total_hits(&self) -> u64 {
+        self.data.iter().map(|data| data.total_hits).sum()
+    }
+
+    pub fn last_activity(&self) -> SystemTime {
+        self.last_activity
+    }
+
+    //How long it has been since the files of the run last grew
+    pub fn idle_time(&self) -> Duration {
+        SystemTime::now().duration_since(self.last_activity).unwrap_or(Duration::ZERO)
+    }
+
+    fn update_activity(&mut self, hits_before: u64) {
+        if self.total_hits() != hits_before {
+            self.last_activity = SystemTime::now();
+        }
+    }
+
+    //Skip all data currently in the files, so that only data written from now on is read
+    pub fn skip_to_end(&mut self) {
+        for data in self.data.iter_mut() {
+            data.skip_to_end();
+        }
+    }
+
+    //Add a newly created file to the data directory it belongs to, if any
+    pub fn add_file(&mut self, path: &Path) {
+        if let Some(data) = self.data.iter_mut().find(|data| path.parent() == Some(data.directory.as_path())) {
+            data.add_file(path);
+        }
+    }
+
+    //Check all data directories for files which have not been picked up yet
+    pub fn rescan(&mut self) -> Result<(), ProjectError> {
+        for data in self.data.iter_mut() {
+            data.rescan()?;
+        }
+        Ok(())
+    }
+
+    //Get messages from all data directories and convert to Bytes
+    pub fn read_data_from_all_files(&mut self) -> Bytes {
+        let hits_before = self.total_hits();
+        let messages: Vec<Message> = self.data.iter_mut()
+            .flat_map(|data| data.read_data_from_all_files())
+            .collect();
+        self.update_activity(hits_before);
+        self.to_bytes(messages)
+    }
+
+    /*
+        Get messages from only the given file and convert to Bytes. A file not known to the run yet
+        (i.e. its creation event was missed) is added first. Paths outside of the data directories give no data.
+     */
+    pub fn read_data_from_file(&mut self, path: &Path) -> Bytes {
+        let hits_before = self.total_hits();
+        let messages = match self.data.iter_mut().find(|data| path.parent() == Some(data.directory.as_path())) {
+            Some(data) => {
+                data.add_file(path);
+                data.read_data_from_file(path)
+            },
+            None => vec![]
+        };
+        self.update_activity(hits_before);
+        self.to_bytes(messages)
+    }
+
+    //Paths of the data files of the run, in the order read_hit takes them
+    pub fn data_files(&self) -> Vec<&Path> {
+        self.data.iter().flat_map(|data| data.data_files.iter().map(|file| file.path())).collect()
+    }
+
+    //Read the next hit of a data file, by its position in data_files. Used to replay a finished run hit by hit.
+    pub fn read_hit(&mut self, mut file: usize) -> Result<Option<CompassHit>, CompassFileError> {
+        for data in self.data.iter_mut() {
+            match data.data_files.get_mut(file) {
+                Some(handle) => return handle.read_hit(),
+                None => file -= data.data_files.len()
+            }
+        }
+        Ok(None)
+    }
+
+    /*
+        Feed hits taken with read_hit (i.e. by a Replay) through the run as if they had just been written,
+        and convert to Bytes. The hits of each file are given together with its path.
+     */
+    pub fn push_hits(&mut self, batch: Vec<(PathBuf, Vec<CompassHit>)>) -> Bytes {
+        let hits_before = self.total_hits();
+        let mut messages = vec![];
+        for data in self.data.iter_mut() {
+            let raw: Vec<Message> = batch.iter()
+                .filter_map(|(path, hits)| data.push_hits(path, hits))
+                .collect();
+            messages.append(&mut data.process_messages(raw));
+        }
+        self.update_activity(hits_before);
+        self.to_bytes(messages)
+    }
+
+    //Get all hits (and events) still held by the time ordering and convert to Bytes. None if not merging.
+    pub fn flush_data(&mut self) -> Option<Bytes> {
+        let messages: Vec<Message> = self.data.iter_mut()
+            .filter_map(|data| data.flush_data())
+            .flatten()
+            .collect();
+        if messages.is_empty() {
+            None
+        } else {
+            Some(self.to_bytes(messages))
+        }
+    }
+
+    //Tag messages with the run number and convert to Bytes
+    fn to_bytes(&self, mut messages: Vec<Message>) -> Bytes {
+        messages.iter_mut().for_each(|mess| mess.run_number = Some(self.id.number));
+        convert_messages_to_bytes(messages)
+    }
+}
+
+/*
+    RunData is a single data subdirectory of a run, and the files within it.
+ */
+#[derive(Debug)]
+struct RunData {
+    origin: DataDirectory,
+    directory: PathBuf,
+    data_files: Vec<CompassFile>,
+    data_type: CompassDataType, //Union of the data types of all files, used for the time ordered stream
+    time_ordered: bool,
+    total_hits: u64,
+    merger: Option<HitMerger>,
+    builder: Option<EventBuilder>
+}
+
+impl RunData {
+
+    fn new(origin: &DataDirectory, data_directory: &Path, options: &RunOptions) -> RunData {
+        RunData {
+            origin: origin.clone(),
+            directory: data_directory.to_path_buf(),
+            data_files: vec![],
+            data_type: CompassDataType::NONE,
+            time_ordered: options.time_ordered,
+            total_hits: 0,
+            merger: options.needs_merge().then(|| HitMerger::new(0, options.hold_back_window)),
+            builder: options.event_builder.as_ref().map(EventBuilder::new)
+        }
+    }
+
+    /*
+        Start reading a CoMPASS binary file, if it is not already being read. Files which were just created
+        may not have their header written yet; these are skipped and picked up again by a later rescan.
+     */
+    fn add_file(&mut self, path: &Path) {
+        if !is_compass_binary(path) || self.data_files.iter().any(|file| file.path() == path) {
+            return;
+        }
+
+        let file = match CompassFile::new(path) {
+            Ok(f) => f,
+            Err(e) => {
+                tracing::trace!("File is not ready to be read yet: {}", e);
+                return;
+            }
+        };
+        tracing::info!("Reading data from file: {}", path.display());
+        self.data_type |= file.data_type();
+        if let Some(merger) = self.merger.as_mut() {
+            merger.add_source();
+        }
+        self.data_files.push(file);
+    }
+
+    fn skip_to_end(&mut self) {
+        for handle in self.data_files.iter_mut() {
+            if let Err(e) = handle.skip_to_end() {
+                tracing::error!("An error occurred skipping file data: {}", e);
+            }
+        }
+    }
+
+    //Add any files in the data directory which are not being read yet. The directory itself may not exist yet.
+    fn rescan(&mut self) -> Result<(), ProjectError> {
+        if !self.directory.is_dir() {
+            return Ok(());
+        }
+        //Sorted so that the files (and their messages) are always in the same order
+        let mut paths = vec![];
+        for item in self.directory.read_dir()? {
+            paths.push(item?.path());
+        }
+        paths.sort();
+        paths.iter().for_each(|path| self.add_file(path));
+        Ok(())
+    }
+
+    //Get messages from files which have grown since they were last read
+    fn read_data_from_all_files(&mut self) -> Vec<Message> {
+        let messages: Vec<Message> = (0..self.data_files.len())
+            .filter_map(|source| self.read_file(source))
+            .collect();
+        self.process_messages(messages)
+    }
+
+    //Get messages from a single file, if it has grown since it was last read
+    fn read_data_from_file(&mut self, path: &Path) -> Vec<Message> {
+        let messages: Vec<Message> = self.data_files.iter()
+            .position(|file| file.path() == path)
+            .and_then(|source| self.read_file(source))
+            .into_iter()
+            .collect();
+        self.process_messages(messages)
+    }
+
+    //Read the new data of a file. When merging, its hits are fed to the time ordering.
+    fn read_file(&mut self, source: usize) -> Option<Message> {
+        let handle = &mut self.data_files[source];
+        match handle.has_new_data() {
+            Ok(true) => {},
+            Ok(false) => return None,
+            Err(e) => {
+                tracing::error!("An error occurred checking file data: {}", e);
+                return None;
+            }
+        }
+        match handle.read_data() {
+            Ok(mess) => {
+                self.total_hits += mess.num_hits;
+                if self.merger.is_some() {
+                    let hits = CompassHit::decode_all(handle.data_type(), &mess.data);
+                    self.merge_hits(source, hits);
+                }
+                Some(mess)
+            },
+            Err(e) => {
+                tracing::error!("An error occurred reading file data: {}", e);
+                None
+            }
+        }
+    }
+
+    //Make the message of hits of a file which were read elsewhere, as if read from the file. Unknown files give no data.
+    fn push_hits(&mut self, path: &Path, hits: &[CompassHit]) -> Option<Message> {
+        let source = self.data_files.iter().position(|file| file.path() == path)?;
+        if hits.is_empty() {
+            return None;
+        }
+        let file = &self.data_files[source];
+        let mut message = Message::from_hits(file.data_type(), hits);
+        message.source = MessageSource { channel: Some(hits[0].channel_id()), ..file.source() };
+        self.total_hits += message.num_hits;
+        self.merge_hits(source, hits.to_vec());
+        Some(message)
+    }
+
+    //Feed the hits of a file to the time ordering, if merging
+    fn merge_hits(&mut self, source: usize, hits: Vec<CompassHit>) {
+        if let Some(merger) = self.merger.as_mut() {
+            hits.into_iter().for_each(|hit| merger.push(source, hit));
+        }
+    }
+
+    /*
+        Take the hits which are ready from the time ordering (hits which may still be preceded by data
+        not yet flushed to other files are held back) and build them into events
+     */
+    fn process_messages(&mut self, raw: Vec<Message>) -> Vec<Message> {
+        let ordered = match self.merger.as_mut() {
+            Some(merger) => merger.pop_ready(),
+            None => vec![]
+        };
+        let events = match self.builder.as_mut() {
+            Some(builder) => builder.push_all(ordered.clone()),
+            None => vec![]
+        };
+        self.build_messages(raw, ordered, events)
+    }
+
+    //Get all hits (and events) still held by the time ordering. None if not merging.
+    fn flush_data(&mut self) -> Option<Vec<Message>> {
+        let ordered = self.merger.as_mut()?.flush();
+        let mut events = vec![];
+        if let Some(builder) = self.builder.as_mut() {
+            events = builder.push_all(ordered.clone());
+            events.extend(builder.flush());
+        }
+        Some(self.build_messages(vec![], ordered, events))
+    }
+
+    //Select the hit stream (raw per-file or time ordered), add any built events, and tag everything with the origin
+    fn build_messages(&self, raw: Vec<Message>, ordered: Vec<CompassHit>, events: Vec<CompassEvent>) -> Vec<Message> {
+        let mut messages = if !self.time_ordered {
+            raw
+        } else if ordered.is_empty() {
+            vec![]
+        } else {
+            vec![Message::from_hits(self.data_type, &ordered)]
+        };
+        if !events.is_empty() {
+            messages.push(Message::from_events(self.data_type, &events));
+        }
+        messages.iter_mut().for_each(|mess| mess.source.origin = self.origin.name().to_string());
+        messages
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::convert_bytes_to_messages;
+
+    fn write_file(path: &Path, hits: &[CompassHit]) {
+        let data_type = CompassDataType::ENERGY;
+        let mut bytes = data_type.bits().to_le_bytes().to_vec();
+        hits.iter().for_each(|hit| bytes.append(&mut hit.encode(data_type)));
+        std::fs::write(path, bytes).unwrap();
+    }
+
+    fn run_id(dir: &Path) -> RunId {
+        RunPattern::default().parse(dir).unwrap()
+    }
+
+    fn options(data_directories: Vec<DataDirectory>) -> RunOptions {
+        RunOptions { data_directories, time_ordered: false, hold_back_window: 0, event_builder: None }
+    }
+
+    #[test]
+    fn reads_each_data_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let run_dir = dir.path().join("run_1");
+        for (name, channel) in [("UNFILTERED", 0), ("FILTERED", 1)] {
+            std::fs::create_dir_all(run_dir.join(name)).unwrap();
+            write_file(&run_dir.join(name).join("DataR_CH0@V1730_1_run_1.BIN"), &[CompassHit { channel, ..Default::default() }]);
+        }
+        std::fs::write(run_dir.join("UNFILTERED").join("notes"), "not data").unwrap();
+
+        let mut run = ActiveRun::new(run_id(&run_dir), &run_dir, &options(vec![DataDirectory::Unfiltered, DataDirectory::Filtered])).unwrap();
+        let messages = convert_bytes_to_messages(&run.read_data_from_all_files()).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert!(messages.iter().all(|m| m.run_number == Some(1)));
+        assert_eq!(messages[0].source.origin, "UNFILTERED");
+        assert_eq!(messages[0].source.channel.unwrap().channel, 0);
+        assert_eq!(messages[1].source.origin, "FILTERED");
+        assert_eq!(messages[1].source.channel.unwrap().channel, 1);
+    }
+
+    #[test]
+    fn picks_up_late_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let run_dir = dir.path().join("run_1");
+        std::fs::create_dir_all(&run_dir).unwrap();
+        let mut run = ActiveRun::new(run_id(&run_dir), &run_dir, &options(vec![DataDirectory::Unfiltered])).unwrap();
+        assert!(convert_bytes_to_messages(&run.read_data_from_all_files()).unwrap().is_empty());
+
+        //Directory and file appear, but the header has not been written yet
+        let data_dir = run_dir.join("UNFILTERED");
+        std::fs::create_dir_all(&data_dir).unwrap();
+        std::fs::write(data_dir.join("DataR_CH0@V1730_1_run_1.BIN"), []).unwrap();
+        run.rescan().unwrap();
+        assert!(convert_bytes_to_messages(&run.read_data_from_all_files()).unwrap().is_empty());
+
+        write_file(&data_dir.join("DataR_CH0@V1730_1_run_1.BIN"), &[CompassHit::default()]);
+        run.rescan().unwrap();
+        write_file(&data_dir.join("DataR_CH1@V1730_1_run_1.BIN"), &[CompassHit { channel: 1, ..Default::default() }]);
+        run.add_file(&data_dir.join("DataR_CH1@V1730_1_run_1.BIN"));
+        run.add_file(&data_dir.join("DataR_CH1@V1730_1_run_1.BIN"));
+
+        let messages = convert_bytes_to_messages(&run.read_data_from_all_files()).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert!(messages.iter().all(|m| m.num_hits == 1));
+    }
+
+    #[test]
+    fn reads_only_the_given_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_dir = dir.path().join("run_1").join("UNFILTERED");
+        std::fs::create_dir_all(&data_dir).unwrap();
+        write_file(&data_dir.join("DataR_CH0@V1730_1_run_1.BIN"), &[CompassHit::default()]);
+        let mut run = ActiveRun::new(run_id(&dir.path().join("run_1")), &dir.path().join("run_1"), &options(vec![DataDirectory::Unfiltered])).unwrap();
+
+        //Created after the run without an event
+        write_file(&data_dir.join("DataR_CH1@V1730_1_run_1.BIN"), &vec![CompassHit { channel: 1, ..Default::default() }; 2]);
+        let messages = convert_bytes_to_messages(&run.read_data_from_file(&data_dir.join("DataR_CH1@V1730_1_run_1.BIN"))).unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].num_hits, 2);
+        assert!(convert_bytes_to_messages(&run.read_data_from_file(&data_dir.join("DataR_CH1@V1730_1_run_1.BIN"))).unwrap().is_empty());
+        assert!(convert_bytes_to_messages(&run.read_data_from_file(&dir.path().join("elsewhere.BIN"))).unwrap().is_empty());
+
+        let messages = convert_bytes_to_messages(&run.read_data_from_all_files()).unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].source.channel.unwrap().channel, 0);
+    }
+
+    #[test]
+    fn summarizes_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let run_dir = dir.path().join("run_7");
+        std::fs::create_dir_all(run_dir.join("UNFILTERED")).unwrap();
+        write_file(&run_dir.join("UNFILTERED").join("DataR_CH0@V1730_1_run_7.BIN"), &vec![CompassHit::default(); 3]);
+
+        let mut run = ActiveRun::new(run_id(&run_dir), &run_dir, &options(vec![DataDirectory::Unfiltered])).unwrap();
+        let idle_since = run.last_activity();
+        run.read_data_from_all_files();
+        assert!(run.last_activity() >= idle_since);
+        run.read_data_from_all_files();
+
+        let stop = SystemTime::now();
+        let info = run.info(Some(stop));
+        assert_eq!(run.id().name, "run_7");
+        assert_eq!(info.run_number, 7);
+        assert_eq!(info.total_hits, 3);
+        assert_eq!(info.stop_time, Some(unix_time_ms(stop)));
+        assert!(info.start_time > 0 && info.start_time <= unix_time_ms(stop));
+        assert_eq!(RunInfo::decode(&info.encode()), Some(info));
+        assert_eq!(RunInfo::decode(&run.info(None).encode()).unwrap().stop_time, None);
+    }
+
+    #[test]
+    fn parses_run_directories() {
+        let pattern = RunPattern::default();
+        assert_eq!(pattern.parse(Path::new("/data/run_12")), Some(RunId { name: "run_12".to_string(), number: 12 }));
+        for name in ["run_backup_old", "my_run_notes", "run_", "run_12_old", "prerun_12", "run_99999999999"] {
+            assert_eq!(pattern.parse(&Path::new("/data").join(name)), None, "{}", name);
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            assert_eq!(pattern.parse(Path::new(std::ffi::OsStr::from_bytes(b"run_\xff"))), None);
+        }
+
+        //Partial matches are not enough, even if the pattern is not anchored
+        let custom = RunPattern::new(r"exp(?<number>\d+)_r\d+").unwrap();
+        assert_eq!(custom.parse(Path::new("exp4_r2")).unwrap().number, 4);
+        assert_eq!(custom.parse(Path::new("exp4_r2_bad")), None);
+
+        assert!(matches!(RunPattern::new("run_("), Err(RunPatternError::InvalidRegex(_))));
+        assert!(matches!(RunPattern::new(r"run_\d+"), Err(RunPatternError::NoRunNumber(_))));
+    }
+}
@@ -1,12 +1,21 @@
 use std::fmt::Display;
 use std::error::Error;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::collections::{BTreeMap, VecDeque};
 use std::sync::Arc;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::io::AsyncWriteExt;
-use tokio::sync::{Mutex, mpsc::{Receiver, Sender, channel}};
+use std::time::SystemTime;
+use tokio::net::TcpListener;
+use tokio::sync::{Mutex, watch, mpsc::{Receiver, Sender, channel}};
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, Instant, Interval, MissedTickBehavior};
 use bytes::Bytes;
 
+use crate::config::{AdmissionPolicy, CatchUpConfig, Config};
+use crate::connection::{Connection, ConnectionOptions, FLUSH_TIMEOUT};
+use crate::file::ChannelId;
+use crate::message::{FrameHeader, Heartbeat, Message, MessageKind, convert_messages_to_bytes, split_frames};
+use crate::run::unix_time_ms;
+
 /*
     This file is kinda crowded, may need a refactor at some point.
  */
@@ -14,23 +23,27 @@ use bytes::Bytes;
 #[derive(Debug)]
 pub enum ServerError {
     StartupError(std::io::Error),
-    SendError(tokio::sync::mpsc::error::SendError<Connection>),
-    ConnectionError(std::io::Error, String)
+    SendError(Box<tokio::sync::mpsc::error::SendError<Connection>>),
+    ConnectionError(std::io::Error, String),
+    ConnectionClosed(String),
+    HeartbeatTimeout(String)
 }
 
 impl Display for ServerError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::StartupError(x) => write!(f, "Server ran into an error on startup: {}", x),
-            Self::SendError(e) => write!(f, "Server ran into a send error: {}\n", e),
-            Self::ConnectionError(e, address) => write!(f, "Server ran into a connection error: {}\n Address of connection: {}", e, address)
+            Self::SendError(e) => writeln!(f, "Server ran into a send error: {}", e),
+            Self::ConnectionError(e, address) => write!(f, "Server ran into a connection error: {}\n Address of connection: {}", e, address),
+            Self::ConnectionClosed(address) => write!(f, "Connection {} is closed", address),
+            Self::HeartbeatTimeout(address) => write!(f, "Connection {} stopped acknowledging heartbeats", address)
         }
     }
 }
 
 impl From<tokio::sync::mpsc::error::SendError<Connection>> for ServerError {
     fn from(value: tokio::sync::mpsc::error::SendError<Connection>) -> Self {
-        Self::SendError(value)
+        Self::SendError(Box::new(value))
     }
 }
 
@@ -38,40 +51,225 @@ impl Error for ServerError {
 
 }
 
-/*
-    A connection is an abstraction of a TCP stream. Our server only 
-    writes to connections; it never reads data sent back to the server.
+//Shorthand type
+type ConnectionList = Vec<Connection>;
+
+/**
+    Options for the server, taken from the Config. At most max_connections clients are served at once,
+    reserved_connections of which only clients connecting from an address in the allow_list can use.
+    A Heartbeat is sent to every client each heartbeat_interval, if given.
  */
-#[derive(Debug)]
-pub struct Connection {
-    stream: TcpStream,
-    address: SocketAddr,
-    is_open: bool
+#[derive(Debug, Clone)]
+pub struct ServerOptions {
+    pub connection: ConnectionOptions,
+    pub max_connections: usize,
+    pub reserved_connections: usize,
+    pub allow_list: Vec<IpAddr>,
+    pub admission_policy: AdmissionPolicy,
+    pub catch_up: Option<CatchUpConfig>,
+    pub heartbeat_interval: Option<Duration>
 }
 
-impl Connection {
+impl ServerOptions {
+    pub fn new(config: &Config) -> Self {
+        ServerOptions {
+            connection: ConnectionOptions::new(config),
+            max_connections: config.max_connections,
+            reserved_connections: config.reserved_connections,
+            allow_list: config.allow_list.clone(),
+            admission_policy: config.admission_policy,
+            catch_up: config.catch_up.clone(),
+            heartbeat_interval: config.heartbeat_interval_ms.filter(|ms| *ms > 0).map(Duration::from_millis)
+        }
+    }
 
-    pub fn new(stream: TcpStream, addr: SocketAddr) -> Self {
-        Connection{ stream: stream, address: addr, is_open: true}
+    pub fn is_allow_listed(&self, address: &SocketAddr) -> bool {
+        self.allow_list.contains(&address.ip())
     }
 
-    pub fn is_open(&self) -> &bool {
-        &self.is_open
+    //Check if there is a free slot for a client; allow-listed clients can also use the reserved slots
+    fn has_room(&self, list: &ConnectionList, allow_listed: bool) -> bool {
+        if list.len() >= self.max_connections {
+            return false;
+        }
+        let open_slots = self.max_connections.saturating_sub(self.reserved_connections);
+        allow_listed || list.iter().filter(|cxn| !self.is_allow_listed(&cxn.address())).count() < open_slots
     }
+}
 
-    pub async fn write(&mut self, data: &Bytes) -> Result<(), ServerError> {
-        match self.stream.write_all(data).await {
-            Ok(()) => Ok(()),
-            Err(e) => { 
-                self.is_open = false;
-                Err(ServerError::ConnectionError(e, self.address.to_string()))
+impl Default for ServerOptions {
+    fn default() -> Self {
+        ServerOptions {
+            connection: ConnectionOptions::default(),
+            max_connections: 5,
+            reserved_connections: 0,
+            allow_list: vec![],
+            admission_policy: AdmissionPolicy::default(),
+            catch_up: None,
+            heartbeat_interval: Some(Duration::from_secs(1))
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct RunFrames {
+    start: Option<Bytes>,
+    metadata: Option<Bytes>,
+    stop: Option<Bytes>,
+    recent: VecDeque<(Instant, Bytes)>,
+    recent_bytes: usize,
+    run_number: Option<u32>,
+    running: bool,
+    counters: BTreeMap<ChannelId, u64>
+}
+
+impl RunFrames {
+    fn count(&mut self, hits: &[(ChannelId, u64)]) {
+        for (id, n_hits) in hits {
+            *self.counters.entry(*id).or_default() += n_hits;
+        }
+    }
+
+    //Drop the oldest data until the buffer is within the limits
+    fn trim(&mut self, limits: &CatchUpConfig, now: Instant) {
+        let max_age = limits.max_age_ms.map(Duration::from_millis);
+        while let Some((time, frame)) = self.recent.front() {
+            let too_old = max_age.is_some_and(|age| now.duration_since(*time) > age);
+            if !too_old && self.recent.len() <= limits.max_messages && self.recent_bytes <= limits.max_bytes {
+                break;
             }
+            self.recent_bytes -= frame.len();
+            self.recent.pop_front();
         }
     }
 }
 
-//Shorthand type
-type ConnectionList = Vec<Connection>;
+//Hits per channel of a Hits frame, decoding merged frames to find the channel of each hit
+fn count_hits(header: &FrameHeader, frame: &[u8]) -> Vec<(ChannelId, u64)> {
+    if header.kind != MessageKind::Hits {
+        return vec![];
+    }
+    if let Some(id) = header.channel {
+        return vec![(id, header.num_hits)];
+    }
+    let mut counts: BTreeMap<ChannelId, u64> = BTreeMap::new();
+    if let Ok((message, _)) = Message::decode(frame) {
+        for hit in message.hits() {
+            *counts.entry(hit.channel_id()).or_default() += 1;
+        }
+    }
+    counts.into_iter().collect()
+}
+
+/*
+    Data split into its frames for RunCache::update, along with the hits per channel of each Hits frame.
+    Merged frames have to be decoded to count their hits, so this is done before taking any lock.
+ */
+#[derive(Debug, Default)]
+pub struct CacheUpdate {
+    frames: Vec<CacheFrame>
+}
+
+#[derive(Debug)]
+struct CacheFrame {
+    header: FrameHeader,
+    frame: Bytes,
+    hits: Vec<(ChannelId, u64)>
+}
+
+impl CacheUpdate {
+    pub fn new(data: &Bytes) -> Self {
+        let frames = match split_frames(data) {
+            Ok(frames) => frames,
+            Err(_) => return CacheUpdate::default()
+        };
+        let frames = frames.into_iter()
+            .map(|(header, frame)| {
+                let hits = count_hits(&header, &frame);
+                CacheFrame { header, frame, hits }
+            })
+            .collect();
+        CacheUpdate { frames }
+    }
+}
+
+/*
+    The run control and metadata frames of the latest run, taken from the data sent to the clients.
+    Kept so that clients which connect part way through a run can ask for them. If catch_up is given,
+    the recent data of the run is also kept (within its limits) to send to clients when they connect.
+    The state of the run and its hits per channel are also kept for the heartbeats.
+ */
+#[derive(Debug, Default)]
+pub struct RunCache {
+    frames: std::sync::Mutex<RunFrames>,
+    catch_up: Option<CatchUpConfig>
+}
+
+impl RunCache {
+
+    pub fn new(catch_up: Option<CatchUpConfig>) -> Self {
+        RunCache { frames: std::sync::Mutex::new(RunFrames::default()), catch_up }
+    }
+
+    //Keep any run frames in the data and count its hits, keeping the data frames too if there is a catch-up buffer
+    pub fn update(&self, update: CacheUpdate) {
+        let now = Instant::now();
+        let mut run = self.frames.lock().unwrap();
+        for CacheFrame { header, frame, hits } in update.frames {
+            match header.kind {
+                MessageKind::RunStart => *run = RunFrames { start: Some(frame.clone()), run_number: header.run_number, running: true, ..Default::default() },
+                MessageKind::Metadata => run.metadata = Some(frame.clone()),
+                MessageKind::RunStop => {
+                    run.running = false;
+                    run.stop = Some(frame.clone());
+                },
+                MessageKind::Hits => run.count(&hits),
+                _ => {}
+            }
+            if self.catch_up.is_some() && matches!(header.kind, MessageKind::Hits | MessageKind::Events) {
+                run.recent_bytes += frame.len();
+                run.recent.push_back((now, frame));
+            }
+        }
+        if let Some(limits) = self.catch_up.as_ref() {
+            run.trim(limits, now);
+        }
+    }
+
+    //The RunStart, Metadata, and RunStop frames of the latest run, those which have been seen
+    pub fn run_frames(&self) -> Bytes {
+        let run = self.frames.lock().unwrap();
+        [&run.start, &run.metadata, &run.stop].into_iter().flatten().flat_map(|frame| frame.iter().copied()).collect::<Vec<u8>>().into()
+    }
+
+    //The Heartbeat for the current state of the run
+    pub fn heartbeat(&self, sequence: u64) -> Heartbeat {
+        let run = self.frames.lock().unwrap();
+        Heartbeat {
+            sequence,
+            server_time: unix_time_ms(SystemTime::now()),
+            run_number: run.run_number,
+            running: run.running,
+            counters: run.counters.iter().map(|(id, hits)| (*id, *hits)).collect()
+        }
+    }
+
+    //What a newly connected client is sent before the live data: the run frames with the recent data of the run in between
+    pub fn catch_up(&self) -> Bytes {
+        let limits = match self.catch_up.as_ref() {
+            Some(limits) => limits,
+            None => return Bytes::new()
+        };
+        let mut run = self.frames.lock().unwrap();
+        run.trim(limits, Instant::now());
+        let frames = [&run.start, &run.metadata].into_iter().flatten()
+            .chain(run.recent.iter().map(|(_, frame)| frame))
+            .chain(run.stop.iter());
+        let mut data = Vec::with_capacity(run.recent_bytes);
+        frames.for_each(|frame| data.extend_from_slice(frame));
+        data.into()
+    }
+}
 
 /*
     ServerListener wraps listening functionality. It does not actively store connections;
@@ -81,22 +279,25 @@ type ConnectionList = Vec<Connection>;
 pub struct ServerListener {
     listener: TcpListener,
     connection_queue: Sender<Connection>,
-    address: SocketAddr
+    address: SocketAddr,
+    options: ConnectionOptions,
+    run_cache: Arc<RunCache>
 }
 
 impl ServerListener {
     
     //Startup server by spawning a listener port. 
-    pub async fn startup(addr: &str) -> Result<(ServerListener, Receiver<Connection>), ServerError> {
+    pub async fn startup(addr: &str, options: ConnectionOptions, run_cache: Arc<RunCache>) -> Result<(ServerListener, Receiver<Connection>), ServerError> {
         let (tx, rx) = channel(5);
-        let listener = match TcpListener::bind(addr).await {
-            Ok(net) => ServerListener { listener: net, connection_queue: tx, address: addr.parse().unwrap() },
+        let listener = match TcpListener::bind(addr).await.and_then(|net| Ok((net.local_addr()?, net))) {
+            Ok((address, net)) => ServerListener { listener: net, connection_queue: tx, address, options, run_cache },
             Err(e) => return Err(ServerError::StartupError(e))
         };
         tracing::info!("Server listening at address: {}", listener.address);
         Ok((listener, rx))
     }
 
+    //Accept connections until the listener fails or the ConnectionHandler is gone
     pub async fn wait_for_connection(&mut self) -> Result<(), ServerError> {
         
         loop {
@@ -108,41 +309,89 @@ impl ServerListener {
                 Err(e) => return Err(ServerError::StartupError(e))
             };
 
-            self.connection_queue.send(Connection::new(stream, address)).await?
+            self.connection_queue.send(Connection::new(stream, address, &self.options, self.run_cache.clone())).await?
         }
     }
 }
 
 /*
     ConnectionHandler recieves incoming connections and adds them to 
-    the list of acitve connections. The maximum number of active connections
-    is limited to 5.
+    the list of acitve connections. The number of active connections is limited
+    by the ServerOptions; clients which are not admitted are sent a Rejected frame and closed.
+    Admitted clients are first sent the catch-up data of the run (if enabled). This happens with the
+    connection list locked, so that no data is missed or sent twice between the catch-up and the live data.
  */
 #[derive(Debug)]
 pub struct ConnectionHandler {
     connection_queue: Receiver<Connection>,
-    connections: Arc<Mutex<ConnectionList>>
+    connections: Arc<Mutex<ConnectionList>>,
+    n_connections: Arc<watch::Sender<usize>>,
+    options: ServerOptions,
+    run_cache: Arc<RunCache>
 }
 
 impl ConnectionHandler {
 
-    pub fn new(conn_queue: Receiver<Connection>, conns: Arc<Mutex<ConnectionList>>) -> ConnectionHandler {
+    pub fn new(conn_queue: Receiver<Connection>, conns: Arc<Mutex<ConnectionList>>, n_conns: Arc<watch::Sender<usize>>, options: ServerOptions, run_cache: Arc<RunCache>) -> ConnectionHandler {
         ConnectionHandler {
             connection_queue: conn_queue,
-            connections: conns
+            connections: conns,
+            n_connections: n_conns,
+            options,
+            run_cache
+        }
+    }
+
+    //Find a slot for a new client, evicting the idlest client if the policy allows. Gives the reason if there is no slot.
+    fn admit(&self, list: &mut ConnectionList, address: &SocketAddr) -> Result<(), String> {
+        let allow_listed = self.options.is_allow_listed(address);
+        if self.options.has_room(list, allow_listed) {
+            return Ok(());
+        }
+
+        if self.options.admission_policy == AdmissionPolicy::EvictIdle {
+            let idlest = list.iter()
+                .enumerate()
+                .filter(|(_, cxn)| !self.options.is_allow_listed(&cxn.address()))
+                .min_by_key(|(_, cxn)| cxn.idle_since())
+                .map(|(index, _)| index);
+            if let Some(index) = idlest {
+                let evicted = list.remove(index);
+                tracing::warn!("Evicting client {} to make room for {}", evicted.address(), address);
+                evicted.reject("Evicted to make room for a new client");
+                if self.options.has_room(list, allow_listed) {
+                    return Ok(());
+                }
+            }
         }
+
+        Err(format!("Max number of connections ({}) reached", self.options.max_connections))
     }
 
+    //Add connections to the list until the listener is closed
     pub async fn recieve_connection(&mut self) -> Result<(), ServerError> {
         loop {
             match self.connection_queue.recv().await {
-                Some(cxn) => {
+                Some(mut cxn) => {
                     let mut list = self.connections.lock().await;
-                    if list.len() == 5 {
-                        tracing::warn!("Max number of connections (5) reached, cannot connect");
-                    } else  {
-                       list.push(cxn);
+                    list.retain(|cxn| { cxn.is_open() });
+                    match self.admit(&mut list, &cxn.address()) {
+                        Ok(()) => {
+                            let catch_up = self.run_cache.catch_up();
+                            if !catch_up.is_empty() {
+                                tracing::info!("Sending {} bytes of catch-up data to client {}", catch_up.len(), cxn.address());
+                                if let Err(e) = cxn.send(&catch_up) {
+                                    tracing::info!("Connection {} recieved the following error: {}. Closing connection.", cxn.address(), e);
+                                }
+                            }
+                            list.push(cxn);
+                        },
+                        Err(reason) => {
+                            tracing::warn!("{}, cannot connect client {}", reason, cxn.address());
+                            cxn.reject(&reason);
+                        }
                     }
+                    self.n_connections.send_replace(list.len());
                 }
                 None => {
                     tracing::info!("Listener was closed");
@@ -155,40 +404,97 @@ impl ConnectionHandler {
 }
 
 /*
-    ServerSender actively sends data to the active connections. ServerSender has
+    ServerSender actively sends data to the active connections, queueing it for the writer task of each connection. ServerSender has
     access to the list of active connections, and must be given a receiving channel
-    for data (Bytes) from the project.
+    for data (Bytes) from the project. Each connection is only sent the data it is subscribed to.
+    Every heartbeat_interval (if given) each connection is also sent a Heartbeat, and connections which
+    have stopped acking heartbeats are dropped.
  */
 #[derive(Debug)]
 pub struct ServerSender {
     data_queue: Receiver<Bytes>,
-    connections: Arc<Mutex<ConnectionList>>
+    connections: Arc<Mutex<ConnectionList>>,
+    n_connections: Arc<watch::Sender<usize>>,
+    run_cache: Arc<RunCache>,
+    heartbeat_interval: Option<Duration>,
+    heartbeat_sequence: u64
 }
+
+//Wait for the next heartbeat, or forever if heartbeats are disabled
+async fn next_heartbeat(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => { interval.tick().await; },
+        None => std::future::pending().await
+    }
+}
+
 impl ServerSender {
 
-    pub fn new(queue: Receiver<Bytes>, conns: Arc<Mutex<ConnectionList>>) -> Self {
-        ServerSender { data_queue: queue, connections: conns }
+    pub fn new(queue: Receiver<Bytes>, conns: Arc<Mutex<ConnectionList>>, n_conns: Arc<watch::Sender<usize>>, run_cache: Arc<RunCache>, heartbeat_interval: Option<Duration>) -> Self {
+        ServerSender { data_queue: queue, connections: conns, n_connections: n_conns, run_cache, heartbeat_interval, heartbeat_sequence: 0 }
+    }
+
+    async fn send_data(&mut self, data: Bytes) {
+        //Merged frames are decoded for the run cache first, so that admitting clients does not wait on it
+        let update = CacheUpdate::new(&data);
+        //Sending only queues the data for each connection, so the lock is held briefly. The cache is updated
+        //under the lock as well, so that a new client gets this data either in its catch-up or live, not both.
+        let mut list = self.connections.lock().await;
+        self.run_cache.update(update);
+        for cxn in list.iter_mut() {
+            match cxn.send(&data) {
+                Ok(()) => {},
+                Err(e) => {
+                    tracing::info!("Connection {} recieved the following error: {}. Closing connection.", cxn.address(), e);
+                }
+            };
+        }
+
+        list.retain(|cxn| { cxn.is_open() });
+        self.n_connections.send_replace(list.len());
     }
 
+    async fn send_heartbeat(&mut self) {
+        self.heartbeat_sequence += 1;
+        let mut list = self.connections.lock().await;
+        let heartbeat = self.run_cache.heartbeat(self.heartbeat_sequence);
+        let data = convert_messages_to_bytes(vec![Message::from_heartbeat(&heartbeat)]);
+        for cxn in list.iter_mut() {
+            if let Err(e) = cxn.send_heartbeat(heartbeat.sequence, &data) {
+                tracing::info!("Connection {} recieved the following error: {}. Closing connection.", cxn.address(), e);
+            }
+        }
+
+        list.retain(|cxn| { cxn.is_open() });
+        self.n_connections.send_replace(list.len());
+    }
+
+    //Send data to every connection until the data channel is closed, with heartbeats in between
     pub async fn wait_for_data(&mut self) -> Result<(), ServerError> {
+        let mut heartbeats = self.heartbeat_interval.map(|period| {
+            let mut interval = tokio::time::interval_at(Instant::now() + period, period);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            interval
+        });
         loop {
-            match self.data_queue.recv().await {
-                Some(data) => {
-                    //Try to hold this lock as short as possible, but shouldn't matter much in real use-cases
-                    let mut list = self.connections.lock().await;
-                    for cxn in list.iter_mut() {
-                        match cxn.write(&data).await {
-                            Ok(()) => {},
-                            Err(e) => {
-                                tracing::info!("Connection {} recieved the following error: {}. Closing connection.", cxn.address, e);
-                            }
-                        };
-                    }
-
-                    list.retain(|cxn| { *cxn.is_open() })
-                },
+            let data = tokio::select! {
+                data = self.data_queue.recv() => data,
+                _ = next_heartbeat(&mut heartbeats) => {
+                    self.send_heartbeat().await;
+                    continue;
+                }
+            };
+            match data {
+                Some(data) => self.send_data(data).await,
                 None =>  {
                     tracing::info!("Sender closed at ServerSender::wait_for_data");
+                    //Give the clients what is left in their queues before stopping
+                    let list = std::mem::take(&mut *self.connections.lock().await);
+                    let deadline = tokio::time::Instant::now() + FLUSH_TIMEOUT;
+                    for cxn in list {
+                        cxn.finish(deadline).await;
+                    }
+                    self.n_connections.send_replace(0);
                     break
                 }
             }
@@ -198,37 +504,351 @@ impl ServerSender {
 
 }
 
-/*
+/**
+    Handle to the running server. Gives the address the server is bound to (i.e. the actual port when
+    binding port 0) and a watch of the number of connected clients.
+ */
+#[derive(Debug)]
+pub struct ServerHandle {
+    address: SocketAddr,
+    n_connections: watch::Receiver<usize>,
+    sender_task: JoinHandle<()>,
+    connection_tasks: Vec<JoinHandle<()>>
+}
+
+impl ServerHandle {
+
+    ///Address the server is listening on
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    ///Watch of the number of connected clients
+    pub fn connections(&self) -> watch::Receiver<usize> {
+        self.n_connections.clone()
+    }
+
+    /**
+        Stop the server. All of the data sent to the server is written out first, so every sender
+        of the data channel must be dropped before calling this, otherwise it will wait forever.
+     */
+    pub async fn shutdown(self) {
+        if let Err(e) = self.sender_task.await {
+            tracing::error!("Sender task failed: {}", e);
+        }
+        for task in self.connection_tasks {
+            task.abort();
+        }
+        tracing::info!("Server at {} shutdown", self.address);
+    }
+}
+
+/**
     run_server wraps the creation of all server components as well as connecting the separate parts.
-    Requires an address for the server listener and a receiving channel for data from the project.
+    Requires an address for the server listener, the server options, and a receiving channel for data from the project.
     This function spawns tokio tasks.
  */
-pub async fn run_server(address: &str, data_reciever: Receiver<Bytes>) -> Result<(), ServerError> {
+pub async fn run_server(address: &str, options: ServerOptions, data_reciever: Receiver<Bytes>) -> Result<ServerHandle, ServerError> {
     let connections = Arc::new(Mutex::new(ConnectionList::new()));
-    let (mut listener, conn_reciever) = ServerListener::startup(address).await?;
-    let mut conn_handler = ConnectionHandler::new(conn_reciever, connections.clone());
-    let mut sender = ServerSender::new(data_reciever, connections.clone());
+    let (n_connections, n_reciever) = watch::channel(0);
+    let n_connections = Arc::new(n_connections);
+    let run_cache = Arc::new(RunCache::new(options.catch_up.clone()));
+    let (mut listener, conn_reciever) = ServerListener::startup(address, options.connection, run_cache.clone()).await?;
+    let bound_address = listener.address;
+    let mut sender = ServerSender::new(data_reciever, connections.clone(), n_connections.clone(), run_cache.clone(), options.heartbeat_interval);
+    let mut conn_handler = ConnectionHandler::new(conn_reciever, connections.clone(), n_connections, options, run_cache);
 
-    tokio::spawn(async move { 
+    let listener_task = tokio::spawn(async move { 
         match listener.wait_for_connection().await {
             Ok(_) => {},
             Err(e) => tracing::error!("Listener error: {}", e)
         } 
     });
 
-    tokio::spawn(async move {
+    let handler_task = tokio::spawn(async move {
         match conn_handler.recieve_connection().await {
             Ok(_) => {},
             Err(e) => tracing::error!("ConnectionHandler error: {}", e)
         }
     });
 
-    tokio::spawn(async move {
+    let sender_task = tokio::spawn(async move {
         match sender.wait_for_data().await {
             Ok(_) => {},
             Err(e) => tracing::error!("Sender error: {}", e)
         }
     });
 
-    Ok(())
-}
\ No newline at end of file
+    Ok(ServerHandle { address: bound_address, n_connections: n_reciever, sender_task, connection_tasks: vec![listener_task, handler_task] })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpSocket, TcpStream};
+    use crate::file::{ChannelId, CompassDataType, CompassHit};
+    use crate::message::{Message, MessageKind, MessageSource, convert_bytes_to_messages, convert_messages_to_bytes};
+    use crate::run::{RunId, RunInfo};
+    use crate::settings::CompassSettings;
+    use crate::subscription::{ChannelSelector, Command};
+
+    async fn start(options: ServerOptions) -> (ServerHandle, Sender<Bytes>) {
+        let (data_sender, data_reciever) = channel(10);
+        (run_server("127.0.0.1:0", options, data_reciever).await.unwrap(), data_sender)
+    }
+
+    //Connect from a particular loopback address, waiting until the server has dealt with the connection
+    async fn connect_from(server: &ServerHandle, ip: [u8; 4]) -> TcpStream {
+        let socket = TcpSocket::new_v4().unwrap();
+        socket.bind(SocketAddr::from((ip, 0))).unwrap();
+        let stream = socket.connect(server.address()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        stream
+    }
+
+    //Everything the server sends until it closes the connection
+    async fn read_until_closed(mut stream: TcpStream) -> Vec<Message> {
+        let mut bytes = vec![];
+        tokio::time::timeout(Duration::from_secs(2), stream.read_to_end(&mut bytes)).await.unwrap().unwrap();
+        convert_bytes_to_messages(&bytes).unwrap()
+    }
+
+    fn data() -> Bytes {
+        convert_messages_to_bytes(vec![Message::default()])
+    }
+
+    #[tokio::test]
+    async fn rejects_clients_when_full() {
+        let (server, data_sender) = start(ServerOptions { max_connections: 1, ..Default::default() }).await;
+        let first = connect_from(&server, [127, 0, 0, 1]).await;
+        let second = connect_from(&server, [127, 0, 0, 1]).await;
+
+        let rejected = read_until_closed(second).await;
+        assert_eq!(rejected.len(), 1);
+        assert_eq!(rejected[0].kind, MessageKind::Rejected);
+        assert_eq!(String::from_utf8(rejected[0].data.clone()).unwrap(), "Max number of connections (1) reached");
+        assert_eq!(*server.connections().borrow(), 1);
+
+        data_sender.send(data()).await.unwrap();
+        drop(data_sender);
+        server.shutdown().await;
+        assert_eq!(read_until_closed(first).await, vec![Message::default()]);
+    }
+
+    #[tokio::test]
+    async fn reserves_slots_for_allow_listed_clients() {
+        let options = ServerOptions { max_connections: 2, reserved_connections: 1, allow_list: vec![IpAddr::from([127, 0, 0, 2])], ..Default::default() };
+        let (server, data_sender) = start(options).await;
+        let _first = connect_from(&server, [127, 0, 0, 1]).await;
+        let second = connect_from(&server, [127, 0, 0, 1]).await;
+        assert_eq!(read_until_closed(second).await[0].kind, MessageKind::Rejected);
+
+        let allowed = connect_from(&server, [127, 0, 0, 2]).await;
+        assert_eq!(*server.connections().borrow(), 2);
+
+        data_sender.send(data()).await.unwrap();
+        drop(data_sender);
+        server.shutdown().await;
+        assert_eq!(read_until_closed(allowed).await, vec![Message::default()]);
+    }
+
+    #[tokio::test]
+    async fn evicts_idlest_client() {
+        let options = ServerOptions { max_connections: 2, admission_policy: AdmissionPolicy::EvictIdle, ..Default::default() };
+        let (server, data_sender) = start(options).await;
+        let oldest = connect_from(&server, [127, 0, 0, 1]).await;
+        let second = connect_from(&server, [127, 0, 0, 1]).await;
+        let newest = connect_from(&server, [127, 0, 0, 1]).await;
+        assert_eq!(*server.connections().borrow(), 2);
+
+        let evicted = read_until_closed(oldest).await;
+        assert_eq!(evicted.len(), 1);
+        assert_eq!(evicted[0].kind, MessageKind::Rejected);
+
+        data_sender.send(data()).await.unwrap();
+        drop(data_sender);
+        server.shutdown().await;
+        assert_eq!(read_until_closed(second).await, vec![Message::default()]);
+        assert_eq!(read_until_closed(newest).await, vec![Message::default()]);
+    }
+
+    #[tokio::test]
+    async fn serves_client_commands() {
+        let (server, data_sender) = start(ServerOptions::default()).await;
+        let run = RunId { name: "run_2".to_string(), number: 2 };
+        let start = Message::from_run_info(MessageKind::RunStart, &run, &RunInfo { run_number: 2, start_time: 5, stop_time: None, total_hits: 0 });
+        let metadata = Message::from_settings(&run, &CompassSettings::default());
+        data_sender.send(convert_messages_to_bytes(vec![start.clone(), metadata.clone()])).await.unwrap();
+
+        //Joins part way through the run
+        let mut client = connect_from(&server, [127, 0, 0, 1]).await;
+        let commands = [Command::RequestMetadata, Command::Subscribe(vec![ChannelSelector { board: 0, channel: 1 }])];
+        client.write_all(&convert_messages_to_bytes(commands.iter().map(|c| c.to_message()).collect())).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let hits: Vec<Message> = (0..3).map(|channel| {
+            let mut message = Message::from_hits(CompassDataType::ENERGY, &[CompassHit { channel, ..Default::default() }]);
+            message.source = MessageSource { channel: Some(ChannelId { board: 0, channel }), ..Default::default() };
+            message
+        }).collect();
+        data_sender.send(convert_messages_to_bytes(hits.clone())).await.unwrap();
+        drop(data_sender);
+        server.shutdown().await;
+        assert_eq!(read_until_closed(client).await, vec![start, metadata, hits[1].clone()]);
+    }
+
+    fn run_start(number: u32) -> Message {
+        let run = RunId { name: format!("run_{}", number), number };
+        Message::from_run_info(MessageKind::RunStart, &run, &RunInfo { run_number: number, start_time: 5, stop_time: None, total_hits: 0 })
+    }
+
+    fn hits(timestamp: u64) -> Message {
+        Message::from_hits(CompassDataType::ENERGY, &[CompassHit { timestamp, ..Default::default() }])
+    }
+
+    fn cache_with(limits: CatchUpConfig, messages: Vec<Message>) -> RunCache {
+        let cache = RunCache::new(Some(limits));
+        messages.into_iter().for_each(|message| cache.update(CacheUpdate::new(&convert_messages_to_bytes(vec![message]))));
+        cache
+    }
+
+    #[test]
+    fn keeps_recent_data_of_the_run() {
+        let metadata = Message::from_settings(&RunId { name: "run_1".to_string(), number: 1 }, &CompassSettings::default());
+        let mut messages = vec![hits(0), run_start(1), metadata.clone()];
+        messages.extend((1..=5).map(hits));
+
+        let limits = CatchUpConfig { max_messages: 3, max_bytes: 1 << 20, max_age_ms: None };
+        let cache = cache_with(limits, messages.clone());
+        let expected = vec![run_start(1), metadata.clone(), hits(3), hits(4), hits(5)];
+        assert_eq!(convert_bytes_to_messages(&cache.catch_up()).unwrap(), expected);
+        assert_eq!(convert_bytes_to_messages(&cache.run_frames()).unwrap(), vec![run_start(1), metadata.clone()]);
+
+        let limits = CatchUpConfig { max_messages: 100, max_bytes: 2 * hits(0).frame_size(), max_age_ms: None };
+        let cache = cache_with(limits, messages.clone());
+        assert_eq!(convert_bytes_to_messages(&cache.catch_up()).unwrap(), vec![run_start(1), metadata.clone(), hits(4), hits(5)]);
+
+        //A new run starts over
+        cache.update(CacheUpdate::new(&convert_messages_to_bytes(vec![run_start(2)])));
+        assert_eq!(convert_bytes_to_messages(&cache.catch_up()).unwrap(), vec![run_start(2)]);
+
+        let limits = CatchUpConfig { max_messages: 100, max_bytes: 1 << 20, max_age_ms: Some(20) };
+        let cache = cache_with(limits, messages);
+        std::thread::sleep(Duration::from_millis(30));
+        cache.update(CacheUpdate::new(&convert_messages_to_bytes(vec![hits(6)])));
+        assert_eq!(convert_bytes_to_messages(&cache.catch_up()).unwrap(), vec![run_start(1), metadata, hits(6)]);
+
+        assert!(RunCache::new(None).catch_up().is_empty());
+    }
+
+    #[tokio::test]
+    async fn late_clients_catch_up() {
+        let catch_up = CatchUpConfig { max_messages: 2, max_bytes: 1 << 20, max_age_ms: None };
+        let (server, data_sender) = start(ServerOptions { catch_up: Some(catch_up), ..Default::default() }).await;
+        let early = connect_from(&server, [127, 0, 0, 1]).await;
+        for message in [run_start(1), hits(1), hits(2), hits(3)] {
+            data_sender.send(convert_messages_to_bytes(vec![message])).await.unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let late = connect_from(&server, [127, 0, 0, 1]).await;
+        data_sender.send(convert_messages_to_bytes(vec![hits(4)])).await.unwrap();
+        drop(data_sender);
+        server.shutdown().await;
+        assert_eq!(read_until_closed(early).await, vec![run_start(1), hits(1), hits(2), hits(3), hits(4)]);
+        assert_eq!(read_until_closed(late).await, vec![run_start(1), hits(2), hits(3), hits(4)]);
+    }
+
+    fn channel_hits(board: u16, channel: u16, n: usize) -> Message {
+        let mut message = Message::from_hits(CompassDataType::ENERGY, &vec![CompassHit { board, channel, ..Default::default() }; n]);
+        message.source = MessageSource { channel: Some(ChannelId { board, channel }), ..Default::default() };
+        message
+    }
+
+    #[test]
+    fn counts_hits_for_heartbeats() {
+        let cache = RunCache::new(None);
+        cache.update(CacheUpdate::new(&convert_messages_to_bytes(vec![run_start(3), channel_hits(0, 1, 2), channel_hits(1, 0, 1)])));
+        //Merged frames have no single channel, so each hit is counted by its own
+        let merged = Message::from_hits(CompassDataType::ENERGY, &[CompassHit { channel: 1, ..Default::default() }, CompassHit { board: 1, ..Default::default() }]);
+        cache.update(CacheUpdate::new(&convert_messages_to_bytes(vec![merged])));
+
+        let heartbeat = cache.heartbeat(4);
+        assert_eq!(heartbeat.run_number, Some(3));
+        assert!(heartbeat.running);
+        assert_eq!(heartbeat.counters, vec![(ChannelId { board: 0, channel: 1 }, 3), (ChannelId { board: 1, channel: 0 }, 2)]);
+
+        let stop = Message::from_run_info(MessageKind::RunStop, &RunId { name: "run_3".to_string(), number: 3 }, &RunInfo::default());
+        cache.update(CacheUpdate::new(&convert_messages_to_bytes(vec![stop])));
+        assert!(!cache.heartbeat(5).running);
+
+        //A new run starts counting over
+        cache.update(CacheUpdate::new(&convert_messages_to_bytes(vec![run_start(4)])));
+        let heartbeat = cache.heartbeat(6);
+        assert_eq!((heartbeat.run_number, heartbeat.running, heartbeat.counters.len()), (Some(4), true, 0));
+    }
+
+    //Read the heartbeats the server sends for a while, acking them if asked to
+    async fn read_heartbeats(stream: &mut TcpStream, duration: Duration, ack: bool) -> Vec<Heartbeat> {
+        let mut heartbeats = vec![];
+        let mut buffer = vec![];
+        let deadline = Instant::now() + duration;
+        loop {
+            let mut chunk = [0; 1024];
+            let n = match tokio::time::timeout_at(deadline, stream.read(&mut chunk)).await {
+                Ok(Ok(n)) if n > 0 => n,
+                _ => return heartbeats
+            };
+            buffer.extend_from_slice(&chunk[..n]);
+            while let Ok((message, size)) = Message::decode(&buffer) {
+                buffer.drain(..size);
+                if let Some(heartbeat) = Heartbeat::from_message(&message) {
+                    if ack {
+                        stream.write_all(&convert_messages_to_bytes(vec![Command::HeartbeatAck(heartbeat.sequence).to_message()])).await.unwrap();
+                    }
+                    heartbeats.push(heartbeat);
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn sends_heartbeats_to_idle_clients() {
+        let (server, data_sender) = start(ServerOptions { heartbeat_interval: Some(Duration::from_millis(20)), ..Default::default() }).await;
+        let mut client = connect_from(&server, [127, 0, 0, 1]).await;
+        data_sender.send(convert_messages_to_bytes(vec![run_start(7), channel_hits(0, 2, 5)])).await.unwrap();
+
+        let heartbeats = read_heartbeats(&mut client, Duration::from_millis(200), false).await;
+        assert!(heartbeats.len() >= 3, "{:?}", heartbeats);
+        assert!(heartbeats.windows(2).all(|pair| pair[1].sequence > pair[0].sequence));
+        let latest = heartbeats.last().unwrap();
+        assert_eq!((latest.run_number, latest.running), (Some(7), true));
+        assert_eq!(latest.counters, vec![(ChannelId { board: 0, channel: 2 }, 5)]);
+        assert!(latest.server_time > 0);
+        assert_eq!(*server.connections().borrow(), 1);
+
+        drop(data_sender);
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn drops_clients_which_stop_acking() {
+        let mut options = ServerOptions { heartbeat_interval: Some(Duration::from_millis(20)), ..Default::default() };
+        options.connection.heartbeat_timeout = Some(Duration::from_millis(100));
+        let (server, data_sender) = start(options).await;
+        let mut acking = connect_from(&server, [127, 0, 0, 1]).await;
+        let silent = connect_from(&server, [127, 0, 0, 1]).await;
+        assert_eq!(*server.connections().borrow(), 2);
+
+        let heartbeats = read_heartbeats(&mut acking, Duration::from_millis(400), true).await;
+        assert!(heartbeats.len() >= 10, "{:?}", heartbeats);
+        assert_eq!(*server.connections().borrow(), 1);
+        let dropped = read_until_closed(silent).await;
+        assert!(dropped.iter().all(|message| message.kind == MessageKind::Heartbeat));
+
+        drop(data_sender);
+        server.shutdown().await;
+    }
+}
@@ -0,0 +1,229 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::file::ChannelId;
+
+//Name of the file CoMPASS stores the acquisition settings in
+pub const SETTINGS_FILE_NAME: &str = "settings.xml";
+
+#[derive(Debug)]
+pub enum SettingsError {
+    IOError(PathBuf, std::io::Error),
+    XmlError(PathBuf, roxmltree::Error)
+}
+
+impl std::fmt::Display for SettingsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::IOError(path, e) => write!(f, "Could not read settings file {}: {}", path.display(), e),
+            Self::XmlError(path, e) => write!(f, "Could not parse settings file {}: {}", path.display(), e)
+        }
+    }
+}
+
+impl std::error::Error for SettingsError {
+
+}
+
+/*
+    The acquisition settings of a CoMPASS project, as stored in settings.xml.
+    Every setting is kept as a key/value pair of strings, exactly as CoMPASS wrote it (i.e. SRV_PARAM_CH_THRESHOLD),
+    so that new CoMPASS parameters (DPP settings, calibration coefficients, ...) are passed along without changes here.
+    Board parameters are the defaults for all of its channels; channel parameters are only the overrides.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct CompassSettings {
+    pub boards: Vec<BoardSettings>
+}
+
+/*
+    Settings of one digitizer. The index is the board number used in the data (the order of the boards in the file).
+ */
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct BoardSettings {
+    pub index: u16,
+    pub model: String,
+    pub serial_number: String,
+    pub parameters: BTreeMap<String, String>,
+    pub channels: Vec<ChannelSettings>
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ChannelSettings {
+    pub channel: u16,
+    pub parameters: BTreeMap<String, String>
+}
+
+impl CompassSettings {
+
+    pub fn read(path: &Path) -> Result<Self, SettingsError> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(t) => t,
+            Err(e) => return Err(SettingsError::IOError(path.to_path_buf(), e))
+        };
+        match Self::parse(&text) {
+            Ok(settings) => Ok(settings),
+            Err(e) => Err(SettingsError::XmlError(path.to_path_buf(), e))
+        }
+    }
+
+    /*
+        Find the settings of a run. CoMPASS keeps a copy of the settings in each run directory, which
+        is preferred over the project settings since those may have been changed after the run.
+     */
+    pub fn find(run_dir: &Path, project_dir: &Path) -> Option<PathBuf> {
+        [run_dir, project_dir].iter()
+            .map(|dir| dir.join(SETTINGS_FILE_NAME))
+            .find(|path| path.is_file())
+    }
+
+    pub fn parse(text: &str) -> Result<Self, roxmltree::Error> {
+        let document = roxmltree::Document::parse(text)?;
+        let boards = document.root_element()
+            .children()
+            .filter(|node| node.has_tag_name("board"))
+            .enumerate()
+            .map(|(index, board)| parse_board(index as u16, board))
+            .collect();
+        Ok(CompassSettings { boards })
+    }
+
+    //The value of a parameter for a channel; the channel override if there is one, otherwise the board default
+    pub fn channel_parameter(&self, id: &ChannelId, key: &str) -> Option<&str> {
+        let board = self.boards.iter().find(|board| board.index == id.board)?;
+        board.channels.iter()
+            .find(|channel| channel.channel == id.channel)
+            .and_then(|channel| channel.parameters.get(key))
+            .or_else(|| board.parameters.get(key))
+            .map(|value| value.as_str())
+    }
+
+    //Encode as JSON, which is what clients receive in the metadata message
+    pub fn to_json(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap_or_default()
+    }
+}
+
+//Text of the named child element, if any
+fn child_text<'a>(node: roxmltree::Node<'a, '_>, name: &str) -> Option<&'a str> {
+    node.children().find(|child| child.has_tag_name(name))?.text().map(|text| text.trim())
+}
+
+/*
+    Collect the <entry><key>..</key><value>..</value></entry> pairs below a node. Depending on the CoMPASS
+    version the value is either text or wrapped in further elements, so the first text below <value> is used.
+ */
+fn parse_entries(node: roxmltree::Node) -> BTreeMap<String, String> {
+    node.descendants()
+        .filter(|entry| entry.has_tag_name("entry"))
+        .filter_map(|entry| {
+            let key = child_text(entry, "key")?;
+            let value = entry.children()
+                .find(|child| child.has_tag_name("value"))?
+                .descendants()
+                .filter_map(|child| child.text())
+                .map(|text| text.trim())
+                .find(|text| !text.is_empty())
+                .unwrap_or("");
+            Some((key.to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+fn parse_board(index: u16, board: roxmltree::Node) -> BoardSettings {
+    let parameters = board.children()
+        .find(|child| child.has_tag_name("parameters"))
+        .map(parse_entries)
+        .unwrap_or_default();
+    let channels = board.children()
+        .filter(|child| child.has_tag_name("channel"))
+        .filter_map(|channel| {
+            let number = child_text(channel, "index")?.parse().ok()?;
+            Some(ChannelSettings { channel: number, parameters: parse_entries(channel) })
+        })
+        .collect();
+
+    BoardSettings {
+        index,
+        model: child_text(board, "modelName").unwrap_or_default().to_string(),
+        serial_number: child_text(board, "serialNumber").unwrap_or_default().to_string(),
+        parameters,
+        channels
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SETTINGS: &str = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<configuration>
+    <board>
+        <modelName>V1730</modelName>
+        <serialNumber>89</serialNumber>
+        <parameters>
+            <entry>
+                <key>SRV_PARAM_CH_THRESHOLD</key>
+                <value><value xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" xsi:type="xs:double">100.0</value><descr>Threshold</descr></value>
+            </entry>
+            <entry>
+                <key>SW_PARAMETER_CH_ENERGY_CALIBRATION_P1</key>
+                <value><value xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" xsi:type="xs:double">1.0</value></value>
+            </entry>
+        </parameters>
+        <channel>
+            <index>3</index>
+            <values>
+                <entry>
+                    <key>SRV_PARAM_CH_THRESHOLD</key>
+                    <value>250.0</value>
+                </entry>
+            </values>
+        </channel>
+    </board>
+    <board>
+        <modelName>V1725</modelName>
+        <serialNumber>12</serialNumber>
+    </board>
+</configuration>
+"#;
+
+    #[test]
+    fn parses_boards_and_channels() {
+        let settings = CompassSettings::parse(SETTINGS).unwrap();
+        assert_eq!(settings.boards.len(), 2);
+        let board = &settings.boards[0];
+        assert_eq!((board.index, board.model.as_str(), board.serial_number.as_str()), (0, "V1730", "89"));
+        assert_eq!(board.parameters.len(), 2);
+        assert_eq!(board.channels.len(), 1);
+        assert_eq!(settings.boards[1].index, 1);
+
+        let threshold = |board, channel| settings.channel_parameter(&ChannelId { board, channel }, "SRV_PARAM_CH_THRESHOLD");
+        assert_eq!(threshold(0, 3), Some("250.0"));
+        assert_eq!(threshold(0, 0), Some("100.0"));
+        assert_eq!(threshold(1, 0), None);
+        assert_eq!(settings.channel_parameter(&ChannelId { board: 0, channel: 3 }, "SW_PARAMETER_CH_ENERGY_CALIBRATION_P1"), Some("1.0"));
+
+        let json: serde_json::Value = serde_json::from_slice(&settings.to_json()).unwrap();
+        assert_eq!(json["boards"][0]["channels"][0]["parameters"]["SRV_PARAM_CH_THRESHOLD"], "250.0");
+    }
+
+    #[test]
+    fn prefers_run_settings() {
+        let dir = tempfile::tempdir().unwrap();
+        let run_dir = dir.path().join("run_1");
+        std::fs::create_dir_all(&run_dir).unwrap();
+        assert_eq!(CompassSettings::find(&run_dir, dir.path()), None);
+
+        std::fs::write(dir.path().join(SETTINGS_FILE_NAME), SETTINGS).unwrap();
+        assert_eq!(CompassSettings::find(&run_dir, dir.path()), Some(dir.path().join(SETTINGS_FILE_NAME)));
+        std::fs::write(run_dir.join(SETTINGS_FILE_NAME), "<configuration/>").unwrap();
+        let path = CompassSettings::find(&run_dir, dir.path()).unwrap();
+        assert_eq!(path, run_dir.join(SETTINGS_FILE_NAME));
+        assert!(CompassSettings::read(&path).unwrap().boards.is_empty());
+
+        std::fs::write(run_dir.join(SETTINGS_FILE_NAME), "<configuration>").unwrap();
+        assert!(matches!(CompassSettings::read(&path), Err(SettingsError::XmlError(_, _))));
+    }
+}
@@ -0,0 +1,414 @@
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::config::ConfigError;
+use crate::file::{CompassDataType, CompassHit};
+use crate::run::RunPattern;
+use crate::settings::SETTINGS_FILE_NAME;
+
+//Digitizer model written to file names and settings
+const SIMULATED_MODEL: &str = "V1730";
+
+//Serial number of the first simulated board; each following board has the next number
+const FIRST_SERIAL_NUMBER: u32 = 100;
+
+//File written to the run directory when a run ends, which can be used as a stop file (see Config::run_stop_files)
+const RUN_INFO_FILE_NAME: &str = "run.info";
+
+#[derive(Debug)]
+pub enum SimulationError {
+    IOError(std::io::Error),
+    DataTypeError(String)
+}
+
+impl From<std::io::Error> for SimulationError {
+    fn from(value: std::io::Error) -> Self {
+        SimulationError::IOError(value)
+    }
+}
+
+impl std::fmt::Display for SimulationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::IOError(e) => write!(f, "Simulation encountered an io error: {}", e),
+            Self::DataTypeError(name) => write!(f, "Unknown CoMPASS data type {}; expected ENERGY, ENERGY_SHORT, ENERGY_CALIBRATED, or WAVES", name)
+        }
+    }
+}
+
+impl std::error::Error for SimulationError {
+
+}
+
+/**
+    The shape of the simulated energy spectrum, in ADC channels.
+ */
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "shape", rename_all = "lowercase")]
+pub enum EnergySpectrum {
+    Flat { min: f64, max: f64 },
+    Gaussian { mean: f64, sigma: f64 },
+    Exponential { slope: f64 }
+}
+
+impl Default for EnergySpectrum {
+    fn default() -> Self {
+        EnergySpectrum::Gaussian { mean: 1000.0, sigma: 50.0 }
+    }
+}
+
+/**
+    Settings for the simulator, read from a yaml file. Every field has a default.
+    boards and channels are the number of digitizers and channels per digitizer; each channel has its own file.
+    rate is the mean number of hits per second in each channel. data_type lists the CoMPASS data type bits to write,
+    with waves_samples samples per hit in waves mode. runs runs of run_duration_ms are made, pause_ms apart,
+    numbered from first_run (by default one past the last run in the project). Data is flushed every flush_interval_ms.
+ */
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SimulationConfig {
+    #[serde(default = "default_boards")]
+    pub boards: u16,
+    #[serde(default = "default_channels")]
+    pub channels: u16,
+    #[serde(default = "default_rate")]
+    pub rate: f64,
+    #[serde(default)]
+    pub spectrum: EnergySpectrum,
+    #[serde(default = "default_data_type")]
+    pub data_type: Vec<String>,
+    #[serde(default = "default_waves_samples")]
+    pub waves_samples: u32,
+    #[serde(default = "default_runs")]
+    pub runs: u32,
+    #[serde(default)]
+    pub first_run: Option<u32>,
+    #[serde(default = "default_run_duration_ms")]
+    pub run_duration_ms: u64,
+    #[serde(default = "default_pause_ms")]
+    pub pause_ms: u64,
+    #[serde(default = "default_flush_interval_ms")]
+    pub flush_interval_ms: u64,
+    #[serde(default)]
+    pub seed: u64
+}
+
+fn default_boards() -> u16 {
+    1
+}
+
+fn default_channels() -> u16 {
+    4
+}
+
+fn default_rate() -> f64 {
+    1000.0
+}
+
+fn default_data_type() -> Vec<String> {
+    vec![String::from("ENERGY"), String::from("ENERGY_SHORT")]
+}
+
+fn default_waves_samples() -> u32 {
+    64
+}
+
+fn default_runs() -> u32 {
+    1
+}
+
+fn default_run_duration_ms() -> u64 {
+    10_000
+}
+
+fn default_pause_ms() -> u64 {
+    1000
+}
+
+fn default_flush_interval_ms() -> u64 {
+    100
+}
+
+impl Default for SimulationConfig {
+    fn default() -> Self {
+        serde_yaml::from_str("{}").expect("Default simulation config is invalid")
+    }
+}
+
+impl SimulationConfig {
+    pub fn compass_data_type(&self) -> Result<CompassDataType, SimulationError> {
+        let mut data_type = CompassDataType::NONE;
+        for name in self.data_type.iter() {
+            match CompassDataType::from_name(name) {
+                Some(flag) => data_type |= flag,
+                None => return Err(SimulationError::DataTypeError(name.clone()))
+            }
+        }
+        Ok(data_type)
+    }
+}
+
+pub fn read_simulation_config_file(filepath: &Path) -> Result<SimulationConfig, ConfigError> {
+    let yaml_str = std::fs::read_to_string(filepath)?;
+
+    Ok(serde_yaml::from_str::<SimulationConfig>(&yaml_str)?)
+}
+
+//Small deterministic random number generator (SplitMix64); the simulation doesn't need anything better
+#[derive(Debug)]
+struct Random {
+    state: u64
+}
+
+impl Random {
+    fn new(seed: u64) -> Self {
+        Random { state: seed }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+
+    //Uniform in (0, 1]
+    fn uniform(&mut self) -> f64 {
+        ((self.next_u64() >> 11) + 1) as f64 / (1u64 << 53) as f64
+    }
+
+    fn gaussian(&mut self, mean: f64, sigma: f64) -> f64 {
+        let (u1, u2) = (self.uniform(), self.uniform());
+        mean + sigma * (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+    }
+
+    fn exponential(&mut self, mean: f64) -> f64 {
+        -mean * self.uniform().ln()
+    }
+
+    fn energy(&mut self, spectrum: &EnergySpectrum) -> u16 {
+        let value = match spectrum {
+            EnergySpectrum::Flat { min, max } => min + (max - min) * self.uniform(),
+            EnergySpectrum::Gaussian { mean, sigma } => self.gaussian(*mean, *sigma),
+            EnergySpectrum::Exponential { slope } => self.exponential(*slope)
+        };
+        value.clamp(0.0, u16::MAX as f64) as u16
+    }
+}
+
+//A simulated channel and the file its hits are written to
+#[derive(Debug)]
+struct SimulatedChannel {
+    board: u16,
+    channel: u16,
+    writer: BufWriter<File>,
+    next_time: f64 //ps since the start of the run
+}
+
+/**
+    Simulator writes a fake CoMPASS project: run directories with an UNFILTERED data directory holding one
+    binary file per channel, to which hits are appended in real time while the run lasts. A settings.xml is
+    written to the project (and copied to each run), and a run.info file marks the end of each run.
+ */
+#[derive(Debug)]
+pub struct Simulator {
+    config: SimulationConfig,
+    project_directory: PathBuf,
+    data_type: CompassDataType,
+    random: Random
+}
+
+impl Simulator {
+
+    pub fn new(project_directory: &Path, config: SimulationConfig) -> Result<Self, SimulationError> {
+        let data_type = config.compass_data_type()?;
+        Ok(Simulator { random: Random::new(config.seed), config, project_directory: project_directory.to_path_buf(), data_type })
+    }
+
+    ///Run all of the configured runs, returning the run directories which were written
+    pub fn run(&mut self) -> Result<Vec<PathBuf>, SimulationError> {
+        std::fs::create_dir_all(&self.project_directory)?;
+        std::fs::write(self.project_directory.join(SETTINGS_FILE_NAME), self.settings_xml())?;
+
+        let first_run = match self.config.first_run {
+            Some(n) => n,
+            None => self.next_run_number()?
+        };
+        let mut run_dirs = vec![];
+        for run_number in first_run..(first_run + self.config.runs) {
+            if !run_dirs.is_empty() {
+                std::thread::sleep(Duration::from_millis(self.config.pause_ms));
+            }
+            run_dirs.push(self.simulate_run(run_number)?);
+        }
+        Ok(run_dirs)
+    }
+
+    //One past the highest run number in the project
+    fn next_run_number(&self) -> Result<u32, SimulationError> {
+        let pattern = RunPattern::default();
+        let mut next = 1;
+        for item in self.project_directory.read_dir()? {
+            if let Some(id) = pattern.parse(&item?.path()) {
+                next = next.max(id.number + 1);
+            }
+        }
+        Ok(next)
+    }
+
+    fn simulate_run(&mut self, run_number: u32) -> Result<PathBuf, SimulationError> {
+        let run_dir = self.project_directory.join(format!("run_{}", run_number));
+        let data_dir = run_dir.join("UNFILTERED");
+        std::fs::create_dir_all(&data_dir)?;
+        std::fs::copy(self.project_directory.join(SETTINGS_FILE_NAME), run_dir.join(SETTINGS_FILE_NAME))?;
+        tracing::info!("Simulating run {}", run_dir.display());
+
+        let mut channels = vec![];
+        for board in 0..self.config.boards {
+            for channel in 0..self.config.channels {
+                let name = format!("DataR_CH{}@{}_{}_run_{}.BIN", channel, SIMULATED_MODEL, FIRST_SERIAL_NUMBER + board as u32, run_number);
+                let mut writer = BufWriter::new(File::create(data_dir.join(name))?);
+                writer.write_all(&self.data_type.bits().to_le_bytes())?;
+                writer.flush()?;
+                let next_time = self.next_interval();
+                channels.push(SimulatedChannel { board, channel, writer, next_time });
+            }
+        }
+
+        let start = Instant::now();
+        let duration = Duration::from_millis(self.config.run_duration_ms);
+        let flush_interval = Duration::from_millis(self.config.flush_interval_ms.max(1));
+        let mut total_hits: u64 = 0;
+        loop {
+            let elapsed = start.elapsed().min(duration);
+            for channel in channels.iter_mut() {
+                total_hits += self.write_hits(channel, elapsed.as_secs_f64() * 1.0e12)?;
+            }
+            if elapsed >= duration {
+                break;
+            }
+            std::thread::sleep(flush_interval.min(duration - elapsed));
+        }
+
+        std::fs::write(run_dir.join(RUN_INFO_FILE_NAME), format!("run={}\nhits={}\n", run_number, total_hits))?;
+        tracing::info!("Finished simulating run {} with {} hits", run_dir.display(), total_hits);
+        Ok(run_dir)
+    }
+
+    //Mean time between hits in a channel is 1/rate; returns ps
+    fn next_interval(&mut self) -> f64 {
+        self.random.exponential(1.0e12 / self.config.rate.max(f64::MIN_POSITIVE))
+    }
+
+    //Write the hits of a channel up to the given time (ps since the start of the run) and flush them to disk
+    fn write_hits(&mut self, channel: &mut SimulatedChannel, until: f64) -> Result<u64, SimulationError> {
+        let mut count = 0;
+        while channel.next_time <= until {
+            let hit = self.make_hit(channel.board, channel.channel, channel.next_time as u64);
+            channel.writer.write_all(&hit.encode(self.data_type))?;
+            channel.next_time += self.next_interval();
+            count += 1;
+        }
+        channel.writer.flush()?;
+        Ok(count)
+    }
+
+    fn make_hit(&mut self, board: u16, channel: u16, timestamp: u64) -> CompassHit {
+        let energy = self.random.energy(&self.config.spectrum);
+        let mut hit = CompassHit {
+            board,
+            channel,
+            timestamp,
+            energy,
+            energy_short: (energy as f64 * 0.8) as u16,
+            energy_calibrated: energy as f64,
+            ..Default::default()
+        };
+        if self.data_type.contains(CompassDataType::WAVES) {
+            hit.waveform_code = 1;
+            //Baseline with a pulse decaying from the energy
+            hit.samples = (0..self.config.waves_samples)
+                .map(|i| 100 + (energy as f64 * (-(i as f64) / 10.0).exp()) as u16)
+                .collect();
+        }
+        hit
+    }
+
+    //Minimal settings.xml for the simulated boards
+    fn settings_xml(&self) -> String {
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\n<configuration>\n");
+        for board in 0..self.config.boards {
+            xml += &format!(
+                "    <board>\n        <modelName>{}</modelName>\n        <serialNumber>{}</serialNumber>\n        <parameters>\n            <entry><key>SIMULATED_RATE</key><value>{}</value></entry>\n        </parameters>\n    </board>\n",
+                SIMULATED_MODEL, FIRST_SERIAL_NUMBER + board as u32, self.config.rate
+            );
+        }
+        xml += "</configuration>\n";
+        xml
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file::CompassFile;
+    use crate::settings::CompassSettings;
+
+    fn config(yaml: &str) -> SimulationConfig {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn reads_config() {
+        let defaults = SimulationConfig::default();
+        assert_eq!((defaults.boards, defaults.channels, defaults.runs), (1, 4, 1));
+        assert_eq!(defaults.compass_data_type().unwrap(), CompassDataType::ENERGY | CompassDataType::ENERGY_SHORT);
+
+        let custom = config("spectrum: { shape: flat, min: 10, max: 20 }\ndata_type: [ENERGY, WAVES]\n");
+        assert_eq!(custom.spectrum, EnergySpectrum::Flat { min: 10.0, max: 20.0 });
+        assert_eq!(custom.compass_data_type().unwrap(), CompassDataType::ENERGY | CompassDataType::WAVES);
+        assert!(config("data_type: [ENERGY, TIME]").compass_data_type().is_err());
+    }
+
+    #[test]
+    fn writes_compass_project() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("run_3")).unwrap();
+        let sim_config = config("boards: 2\nchannels: 2\nrate: 2000\nruns: 2\nrun_duration_ms: 50\npause_ms: 0\nflush_interval_ms: 10\nspectrum: { shape: flat, min: 100, max: 200 }\n");
+        let runs = Simulator::new(dir.path(), sim_config).unwrap().run().unwrap();
+        assert_eq!(runs, vec![dir.path().join("run_4"), dir.path().join("run_5")]);
+
+        let settings = CompassSettings::read(&dir.path().join("run_4").join(SETTINGS_FILE_NAME)).unwrap();
+        assert_eq!(settings.boards.len(), 2);
+        assert_eq!(settings.boards[1].serial_number, "101");
+
+        let data_dir = dir.path().join("run_4").join("UNFILTERED");
+        assert!(dir.path().join("run_4").join(RUN_INFO_FILE_NAME).is_file());
+        assert_eq!(data_dir.read_dir().unwrap().count(), 4);
+        let mut file = CompassFile::new(&data_dir.join("DataR_CH1@V1730_101_run_4.BIN")).unwrap();
+        assert_eq!(file.data_type(), CompassDataType::ENERGY | CompassDataType::ENERGY_SHORT);
+        let hits: Vec<CompassHit> = file.hits().map(|hit| hit.unwrap()).collect();
+        //50 ms at 2 kHz
+        assert!(hits.len() > 20 && hits.len() < 400, "{} hits", hits.len());
+        assert!(hits.iter().all(|hit| hit.board == 1 && hit.channel == 1));
+        assert!(hits.iter().all(|hit| (100..=200).contains(&hit.energy)));
+        assert!(hits.windows(2).all(|pair| pair[0].timestamp <= pair[1].timestamp));
+        assert!(hits.last().unwrap().timestamp <= 50_000_000_000);
+    }
+
+    #[test]
+    fn writes_waves() {
+        let dir = tempfile::tempdir().unwrap();
+        let sim_config = config("channels: 1\nrate: 1000\nrun_duration_ms: 20\nfirst_run: 7\ndata_type: [ENERGY, WAVES]\nwaves_samples: 16\n");
+        let runs = Simulator::new(dir.path(), sim_config).unwrap().run().unwrap();
+        assert_eq!(runs, vec![dir.path().join("run_7")]);
+
+        let mut file = CompassFile::new(&runs[0].join("UNFILTERED").join("DataR_CH0@V1730_100_run_7.BIN")).unwrap();
+        let hits: Vec<CompassHit> = file.hits().map(|hit| hit.unwrap()).collect();
+        assert!(!hits.is_empty());
+        assert!(hits.iter().all(|hit| hit.samples.len() == 16));
+    }
+}
@@ -0,0 +1,368 @@
+use bitflags::bitflags;
+use bytes::Bytes;
+
+use crate::event::CompassEvent;
+use crate::file::{ChannelId, CompassDataType, CompassHit};
+use crate::message::{FrameHeader, Message, MessageKind, SOURCE_ANY, split_frames};
+
+#[derive(Debug)]
+pub enum CommandError {
+    NotACommand(MessageKind),
+    BadPayload(MessageKind)
+}
+
+impl std::fmt::Display for CommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotACommand(kind) => write!(f, "{:?} is not a client command", kind),
+            Self::BadPayload(kind) => write!(f, "{:?} command has a malformed payload", kind)
+        }
+    }
+}
+
+impl std::error::Error for CommandError {
+
+}
+
+bitflags! {
+    /**
+        The streams a client receives. RAW: Hits frames as read from the CoMPASS files.
+        DECODED: the same hits as DecodedHits frames. EVENTS: Events frames, if the event builder is enabled.
+     */
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Streams: u16 {
+        const RAW = 0x0001;
+        const DECODED = 0x0002;
+        const EVENTS = 0x0004;
+    }
+}
+
+//What every client received before subscriptions existed
+impl Default for Streams {
+    fn default() -> Self {
+        Streams::RAW | Streams::EVENTS
+    }
+}
+
+/**
+    A board/channel pattern a client subscribes to. SOURCE_ANY as the board or channel matches any.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ChannelSelector {
+    pub board: u16,
+    pub channel: u16
+}
+
+impl ChannelSelector {
+    ///Matches every channel of every board
+    pub const ALL: ChannelSelector = ChannelSelector { board: SOURCE_ANY, channel: SOURCE_ANY };
+
+    pub fn matches(&self, id: &ChannelId) -> bool {
+        (self.board == SOURCE_ANY || self.board == id.board) && (self.channel == SOURCE_ANY || self.channel == id.channel)
+    }
+}
+
+impl From<ChannelId> for ChannelSelector {
+    fn from(value: ChannelId) -> Self {
+        ChannelSelector { board: value.board, channel: value.channel }
+    }
+}
+
+/**
+    Commands a client sends to the server, as frames of the corresponding MessageKind. Payloads are little endian.
+    Subscribe/Unsubscribe: a list of (board u16, channel u16) ChannelSelectors. Unsubscribing ChannelSelector::ALL
+    unsubscribes from everything.
+    SelectStreams: the Streams (u16) to receive.
+    RequestMetadata: no payload. The server replies with the RunStart, Metadata, and RunStop (once it has stopped)
+    frames of the latest run, if there has been one.
+    HeartbeatAck: the sequence number (u64) of the latest Heartbeat the client has received.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Subscribe(Vec<ChannelSelector>),
+    Unsubscribe(Vec<ChannelSelector>),
+    SelectStreams(Streams),
+    RequestMetadata,
+    HeartbeatAck(u64)
+}
+
+impl Command {
+
+    pub fn from_message(message: &Message) -> Result<Command, CommandError> {
+        match message.kind {
+            MessageKind::Subscribe => Ok(Command::Subscribe(decode_selectors(message)?)),
+            MessageKind::Unsubscribe => Ok(Command::Unsubscribe(decode_selectors(message)?)),
+            MessageKind::SelectStreams => match <[u8; 2]>::try_from(message.data.as_slice()) {
+                Ok(bits) => Ok(Command::SelectStreams(Streams::from_bits_truncate(u16::from_le_bytes(bits)))),
+                Err(_) => Err(CommandError::BadPayload(message.kind))
+            },
+            MessageKind::RequestMetadata => Ok(Command::RequestMetadata),
+            MessageKind::HeartbeatAck => match <[u8; 8]>::try_from(message.data.as_slice()) {
+                Ok(sequence) => Ok(Command::HeartbeatAck(u64::from_le_bytes(sequence))),
+                Err(_) => Err(CommandError::BadPayload(message.kind))
+            },
+            kind => Err(CommandError::NotACommand(kind))
+        }
+    }
+
+    ///The frame to send to the server for this command
+    pub fn to_message(&self) -> Message {
+        let (kind, data) = match self {
+            Command::Subscribe(selectors) => (MessageKind::Subscribe, encode_selectors(selectors)),
+            Command::Unsubscribe(selectors) => (MessageKind::Unsubscribe, encode_selectors(selectors)),
+            Command::SelectStreams(streams) => (MessageKind::SelectStreams, streams.bits().to_le_bytes().to_vec()),
+            Command::RequestMetadata => (MessageKind::RequestMetadata, vec![]),
+            Command::HeartbeatAck(sequence) => (MessageKind::HeartbeatAck, sequence.to_le_bytes().to_vec())
+        };
+        Message { kind, data, ..Default::default() }
+    }
+}
+
+fn encode_selectors(selectors: &[ChannelSelector]) -> Vec<u8> {
+    selectors.iter().flat_map(|s| [s.board.to_le_bytes(), s.channel.to_le_bytes()].concat()).collect()
+}
+
+fn decode_selectors(message: &Message) -> Result<Vec<ChannelSelector>, CommandError> {
+    if message.data.len() % 4 != 0 {
+        return Err(CommandError::BadPayload(message.kind));
+    }
+    Ok(message.data.chunks_exact(4)
+        .map(|c| ChannelSelector { board: u16::from_le_bytes([c[0], c[1]]), channel: u16::from_le_bytes([c[2], c[3]]) })
+        .collect())
+}
+
+/*
+    What a client has asked to receive. channels is None until the client first subscribes, meaning every channel.
+    Run control and metadata frames are always sent. Frames from a single channel are passed on or not as a whole;
+    merged frames (time ordered hits, events) are cut down to the hits (or the events with a hit) from subscribed channels.
+ */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Subscription {
+    channels: Option<Vec<ChannelSelector>>,
+    streams: Streams
+}
+
+impl Subscription {
+
+    pub fn streams(&self) -> Streams {
+        self.streams
+    }
+
+    pub fn apply(&mut self, command: &Command) {
+        match command {
+            Command::Subscribe(selectors) => {
+                let channels = self.channels.get_or_insert_with(Vec::new);
+                for selector in selectors {
+                    if !channels.contains(selector) {
+                        channels.push(*selector);
+                    }
+                }
+            },
+            Command::Unsubscribe(selectors) => {
+                if selectors.contains(&ChannelSelector::ALL) {
+                    self.channels = Some(vec![]);
+                } else if let Some(channels) = self.channels.as_mut() {
+                    channels.retain(|c| !selectors.contains(c));
+                }
+            },
+            Command::SelectStreams(streams) => self.streams = *streams,
+            Command::RequestMetadata | Command::HeartbeatAck(_) => {}
+        }
+    }
+
+    pub fn wants_channel(&self, id: &ChannelId) -> bool {
+        match self.channels.as_ref() {
+            Some(channels) => channels.iter().any(|c| c.matches(id)),
+            None => true
+        }
+    }
+
+    //Nothing needs filtering or converting
+    fn is_everything(&self) -> bool {
+        self.channels.is_none() && self.streams == Streams::default()
+    }
+
+    //The part of the data (whole frames, as sent by the Project) this subscription wants
+    pub fn filter(&self, data: &Bytes) -> Bytes {
+        if self.is_everything() {
+            return data.clone();
+        }
+
+        let frames = match split_frames(data) {
+            Ok(frames) => frames,
+            Err(e) => {
+                tracing::error!("Data for the clients is not a whole number of frames: {}", e);
+                return Bytes::new();
+            }
+        };
+        let mut selected = vec![];
+        for (header, frame) in frames {
+            self.select(&header, &frame, &mut selected);
+        }
+        Bytes::from(selected)
+    }
+
+    //Append the part of the frame this subscription wants
+    fn select(&self, header: &FrameHeader, frame: &[u8], selected: &mut Vec<u8>) {
+        match header.kind {
+            MessageKind::Hits => {
+                if !self.streams.intersects(Streams::RAW | Streams::DECODED) {
+                    return;
+                }
+                if header.channel.is_some_and(|id| !self.wants_channel(&id)) {
+                    return;
+                }
+                //Frames from a single channel (or any frame, without channel subscriptions) are wanted whole
+                let whole = header.channel.is_some() || self.channels.is_none();
+                if whole && !self.streams.contains(Streams::DECODED) {
+                    selected.extend_from_slice(frame);
+                    return;
+                }
+                let message = match Message::decode(frame) {
+                    Ok((message, _)) if whole => message,
+                    Ok((message, _)) => self.filter_hits(&message),
+                    Err(_) => return
+                };
+                if message.num_hits == 0 && !whole {
+                    return;
+                }
+                //Re-encoding what was decoded from a frame always fits the header
+                if self.streams.contains(Streams::RAW) {
+                    _ = message.encode(selected);
+                }
+                if let Some(decoded) = message.to_decoded().filter(|_| self.streams.contains(Streams::DECODED)) {
+                    _ = decoded.encode(selected);
+                }
+            },
+            MessageKind::Events => {
+                if !self.streams.contains(Streams::EVENTS) {
+                    return;
+                }
+                if self.channels.is_none() {
+                    selected.extend_from_slice(frame);
+                    return;
+                }
+                if let Ok((message, _)) = Message::decode(frame) {
+                    let message = self.filter_events(&message);
+                    if message.num_hits > 0 {
+                        _ = message.encode(selected);
+                    }
+                }
+            },
+            _ => selected.extend_from_slice(frame)
+        }
+    }
+
+    fn filter_hits(&self, message: &Message) -> Message {
+        let hits: Vec<CompassHit> = message.hits().into_iter().filter(|hit| self.wants_channel(&hit.channel_id())).collect();
+        let mut filtered = Message::from_hits(CompassDataType::from_bits_truncate(message.data_type), &hits);
+        filtered.source = message.source.clone();
+        filtered.run_number = message.run_number;
+        filtered
+    }
+
+    fn filter_events(&self, message: &Message) -> Message {
+        let events: Vec<CompassEvent> = message.events().into_iter()
+            .filter(|event| event.hits.iter().any(|hit| self.wants_channel(&hit.channel_id())))
+            .collect();
+        let mut filtered = Message::from_events(CompassDataType::from_bits_truncate(message.data_type), &events);
+        filtered.source = message.source.clone();
+        filtered.run_number = message.run_number;
+        filtered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{MessageSource, convert_bytes_to_messages, convert_messages_to_bytes};
+    use crate::run::{RunId, RunInfo};
+
+    const DATA_TYPE: CompassDataType = CompassDataType::ENERGY;
+
+    fn hit(board: u16, channel: u16, timestamp: u64) -> CompassHit {
+        CompassHit { board, channel, timestamp, energy: 100 + channel, ..Default::default() }
+    }
+
+    fn file_message(board: u16, channel: u16) -> Message {
+        let mut message = Message::from_hits(DATA_TYPE, &[hit(board, channel, 1), hit(board, channel, 2)]);
+        message.source = MessageSource { channel: Some(ChannelId { board, channel }), file: format!("CH{}", channel), origin: "UNFILTERED".to_string() };
+        message.run_number = Some(3);
+        message
+    }
+
+    fn data() -> Vec<Message> {
+        let run = RunId { name: "run_3".to_string(), number: 3 };
+        let start = Message::from_run_info(MessageKind::RunStart, &run, &RunInfo { run_number: 3, start_time: 1, stop_time: None, total_hits: 0 });
+        let mut merged = Message::from_hits(DATA_TYPE, &[hit(0, 0, 1), hit(0, 1, 2), hit(1, 0, 3)]);
+        merged.run_number = Some(3);
+        let events = Message::from_events(DATA_TYPE, &[
+            CompassEvent { hits: vec![hit(0, 0, 1), hit(0, 1, 2)] },
+            CompassEvent { hits: vec![hit(1, 0, 10), hit(1, 1, 11)] }
+        ]);
+        vec![start, file_message(0, 0), file_message(0, 1), file_message(1, 0), merged, events]
+    }
+
+    fn filtered(commands: &[Command]) -> Vec<Message> {
+        let mut subscription = Subscription::default();
+        commands.iter().for_each(|command| subscription.apply(command));
+        convert_bytes_to_messages(&subscription.filter(&convert_messages_to_bytes(data()))).unwrap()
+    }
+
+    #[test]
+    fn round_trips_commands() {
+        let commands = [
+            Command::Subscribe(vec![ChannelSelector { board: 0, channel: 1 }, ChannelSelector { board: 2, channel: SOURCE_ANY }]),
+            Command::Unsubscribe(vec![ChannelSelector::ALL]),
+            Command::SelectStreams(Streams::DECODED | Streams::EVENTS),
+            Command::RequestMetadata,
+            Command::HeartbeatAck(17)
+        ];
+        for command in commands {
+            assert_eq!(Command::from_message(&command.to_message()).unwrap(), command);
+        }
+        assert!(matches!(Command::from_message(&Message::default()), Err(CommandError::NotACommand(MessageKind::Hits))));
+        let bad = Message { kind: MessageKind::Subscribe, data: vec![0, 1, 2], ..Default::default() };
+        assert!(matches!(Command::from_message(&bad), Err(CommandError::BadPayload(_))));
+    }
+
+    #[test]
+    fn passes_everything_by_default() {
+        assert_eq!(filtered(&[]), data());
+    }
+
+    #[test]
+    fn filters_by_channel() {
+        let messages = filtered(&[Command::Subscribe(vec![ChannelSelector { board: 0, channel: SOURCE_ANY }])]);
+        let expected = data();
+        assert_eq!(messages.len(), 5);
+        assert_eq!(messages[0], expected[0]);
+        assert_eq!(&messages[1..3], &expected[1..3]);
+        let merged: Vec<ChannelId> = messages[3].hits().iter().map(|h| h.channel_id()).collect();
+        assert_eq!(merged, vec![ChannelId { board: 0, channel: 0 }, ChannelId { board: 0, channel: 1 }]);
+        assert_eq!(messages[3].run_number, Some(3));
+        assert_eq!(messages[4].events(), vec![CompassEvent { hits: vec![hit(0, 0, 1), hit(0, 1, 2)] }]);
+
+        let messages = filtered(&[Command::Subscribe(vec![ChannelSelector { board: 5, channel: 5 }])]);
+        assert_eq!(messages, vec![data()[0].clone()]);
+        let messages = filtered(&[Command::Subscribe(vec![ChannelSelector { board: 0, channel: 0 }]), Command::Unsubscribe(vec![ChannelSelector::ALL])]);
+        assert_eq!(messages, vec![data()[0].clone()]);
+    }
+
+    #[test]
+    fn converts_streams() {
+        let messages = filtered(&[
+            Command::Subscribe(vec![ChannelSelector { board: 1, channel: 0 }]),
+            Command::SelectStreams(Streams::DECODED)
+        ]);
+        let kinds: Vec<MessageKind> = messages.iter().map(|m| m.kind).collect();
+        assert_eq!(kinds, vec![MessageKind::RunStart, MessageKind::DecodedHits, MessageKind::DecodedHits]);
+        assert_eq!(messages[1].source, file_message(1, 0).source);
+        assert_eq!(messages[1].hits(), vec![hit(1, 0, 1), hit(1, 0, 2)]);
+        assert_eq!(messages[2].hits(), vec![hit(1, 0, 3)]);
+
+        let messages = filtered(&[Command::SelectStreams(Streams::EVENTS)]);
+        let kinds: Vec<MessageKind> = messages.iter().map(|m| m.kind).collect();
+        assert_eq!(kinds, vec![MessageKind::RunStart, MessageKind::Events]);
+    }
+}
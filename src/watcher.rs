@@ -1,25 +1,163 @@
-use notify::{Watcher, RecommendedWatcher, Config};
+use notify::{Watcher, RecommendedWatcher, PollWatcher, RecursiveMode, Config};
 use notify::event::Event;
 use tokio::sync::mpsc::Sender;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::config::WatchMode;
+
+//Notify uses a closure callback to handle events. Note the use of blocking_send to bridge the synchronous code.
+fn forward_events(queue: Sender<Event>) -> impl FnMut(notify::Result<Event>) + Send + 'static {
+    move |event| {
+        if let Ok(data) = event {
+            tracing::trace!("Received an event: {:?}", data);
+            match queue.blocking_send(data) {
+                Ok(()) => { tracing::trace!("Sent!")},
+                Err(_) => tracing::error!("RitualWatcher ran into an error trying to send an event!")
+            };
+        }
+    }
+}
 
 /*
-    create_watcher wraps Notify initialization. Note the use of blocking_send to bridge the synchronous code.
-    Requires a sender for Notify::Events. 
+    create_watcher wraps Notify initialization. Requires a sender for Notify::Events.
+    Native mode uses the OS file events (inotify, FSEvents, etc.). These are not delivered for changes made by
+    other machines to network shares (NFS, SMB), so poll mode instead checks the watched paths every poll_interval.
  */
-pub fn create_watcher(queue: Sender<Event>) -> Result<Box<dyn Watcher>, notify::Error> {
-
-    //Notify uses a closure callback to handle events
-    let ritual = Box::new(RecommendedWatcher::new(move |event| 
-        {
-            if let Ok(data) = event {
-                tracing::trace!("Received an event: {:?}", data);
-                match queue.blocking_send(data) {
-                    Ok(()) => { tracing::trace!("Sent!")},
-                    Err(_) => tracing::error!("RitualWatcher ran into an error trying to send an event!")
-                };
-            }
-        }, 
-        Config::default()
-    )?);
+pub fn create_watcher(queue: Sender<Event>, mode: WatchMode, poll_interval: Duration) -> Result<Box<dyn Watcher + Send>, notify::Error> {
+    let ritual: Box<dyn Watcher + Send> = match mode {
+        WatchMode::Native => Box::new(RecommendedWatcher::new(forward_events(queue), Config::default())?),
+        WatchMode::Poll => Box::new(PollWatcher::new(forward_events(queue), Config::default().with_poll_interval(poll_interval))?)
+    };
     Ok(ritual)
-}
\ No newline at end of file
+}
+
+/**
+    ProjectWatcher owns the Notify watcher along with the set of paths it is watching.
+    Paths can be added and removed at runtime, for example to narrow the watch down to the active run.
+ */
+pub struct ProjectWatcher {
+    watcher: Box<dyn Watcher + Send>,
+    watched: HashMap<PathBuf, RecursiveMode>
+}
+
+impl std::fmt::Debug for ProjectWatcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ProjectWatcher").field("watched", &self.watched).finish()
+    }
+}
+
+impl ProjectWatcher {
+
+    ///Create a watcher sending its events to the queue. Nothing is watched until ProjectWatcher::watch is called.
+    pub fn new(queue: Sender<Event>, mode: WatchMode, poll_interval: Duration) -> Result<Self, notify::Error> {
+        Ok(ProjectWatcher { watcher: create_watcher(queue, mode, poll_interval)?, watched: HashMap::new() })
+    }
+
+    ///Start watching a path. Watching a path which is already watched only updates its mode.
+    pub fn watch(&mut self, path: &Path, mode: RecursiveMode) -> Result<(), notify::Error> {
+        if let Some(current) = self.watched.get(path) {
+            if *current == mode {
+                return Ok(());
+            }
+            self.watcher.unwatch(path)?;
+        }
+        self.watcher.watch(path, mode)?;
+        self.watched.insert(path.to_path_buf(), mode);
+        tracing::info!("Watching {}", path.display());
+        Ok(())
+    }
+
+    ///Stop watching a path. Paths which are not watched are ignored.
+    pub fn unwatch(&mut self, path: &Path) -> Result<(), notify::Error> {
+        if self.watched.remove(path).is_some() {
+            self.watcher.unwatch(path)?;
+            tracing::info!("Stopped watching {}", path.display());
+        }
+        Ok(())
+    }
+
+    ///Stop watching everything
+    pub fn unwatch_all(&mut self) -> Result<(), notify::Error> {
+        let paths: Vec<PathBuf> = self.watched.keys().cloned().collect();
+        for path in paths {
+            self.unwatch(&path)?;
+        }
+        Ok(())
+    }
+
+    ///Check if the path is watched (directly, not as a child of a recursively watched path)
+    pub fn is_watching(&self, path: &Path) -> bool {
+        self.watched.contains_key(path)
+    }
+
+    ///All of the directly watched paths
+    pub fn watched_paths(&self) -> impl Iterator<Item = &Path> {
+        self.watched.keys().map(|p| p.as_path())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::sync::mpsc::Receiver;
+
+    //Wait for an event which involves the given path, returning all of the paths seen until then
+    async fn wait_for_path(queue: &mut Receiver<Event>, path: &Path) -> Option<Vec<PathBuf>> {
+        let wait = async {
+            let mut seen = vec![];
+            while let Some(event) = queue.recv().await {
+                seen.extend(event.paths);
+                if seen.iter().any(|p| p == path) {
+                    return Some(seen);
+                }
+            }
+            None
+        };
+        tokio::time::timeout(Duration::from_secs(2), wait).await.ok().flatten()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn tracks_watched_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = dir.path().join("first");
+        let second = dir.path().join("second");
+        std::fs::create_dir_all(&first).unwrap();
+        std::fs::create_dir_all(&second).unwrap();
+
+        let (tx, mut rx) = tokio::sync::mpsc::channel(32);
+        let mut watcher = ProjectWatcher::new(tx, WatchMode::Native, Duration::from_secs(1)).unwrap();
+        watcher.watch(&first, RecursiveMode::Recursive).unwrap();
+        watcher.watch(&second, RecursiveMode::NonRecursive).unwrap();
+        assert!(watcher.is_watching(&first) && watcher.is_watching(&second));
+        assert_eq!(watcher.watched_paths().count(), 2);
+
+        std::fs::write(first.join("a.BIN"), [0, 0]).unwrap();
+        assert!(wait_for_path(&mut rx, &first.join("a.BIN")).await.is_some());
+
+        watcher.unwatch(&first).unwrap();
+        assert!(!watcher.is_watching(&first));
+        std::fs::write(first.join("b.BIN"), [0, 0]).unwrap();
+        std::fs::write(second.join("c.BIN"), [0, 0]).unwrap();
+        let seen = wait_for_path(&mut rx, &second.join("c.BIN")).await.unwrap();
+        assert!(!seen.contains(&first.join("b.BIN")));
+
+        watcher.unwatch_all().unwrap();
+        assert_eq!(watcher.watched_paths().count(), 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn polls_for_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let (tx, mut rx) = tokio::sync::mpsc::channel(32);
+        let mut watcher = ProjectWatcher::new(tx, WatchMode::Poll, Duration::from_millis(20)).unwrap();
+        watcher.watch(dir.path(), RecursiveMode::Recursive).unwrap();
+
+        std::fs::create_dir_all(dir.path().join("run_1")).unwrap();
+        assert!(wait_for_path(&mut rx, &dir.path().join("run_1")).await.is_some());
+        std::fs::write(dir.path().join("run_1").join("a.BIN"), [0, 0]).unwrap();
+        assert!(wait_for_path(&mut rx, &dir.path().join("run_1").join("a.BIN")).await.is_some());
+    }
+}
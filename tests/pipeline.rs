@@ -0,0 +1,176 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+
+use ritual::Ritual;
+use ritual::config::Config;
+use ritual::file::{CompassDataType, CompassHit};
+use ritual::message::{Message, MessageError, MessageKind};
+use ritual::run::RunInfo;
+use ritual::simulate::{SimulationConfig, Simulator};
+
+/*
+    End to end tests: ritual runs in-process against a temporary project directory, data is written
+    to the project as CoMPASS would, and a TCP client checks the frames it receives.
+    Runs are ended with a run.info stop file, which is what the simulator writes at the end of a run.
+ */
+
+fn config(project: &Path) -> Config {
+    let yaml = format!(
+        "server_address: 127.0.0.1:0\nproject_directory: {}\nrescan_interval_ms: 20\ntail_interval_ms: 20\nrun_stop_files: [run.info]\n",
+        project.display()
+    );
+    serde_yaml::from_str(&yaml).unwrap()
+}
+
+//TCP client which decodes the frames sent by ritual
+struct Client {
+    stream: TcpStream,
+    buffer: Vec<u8>
+}
+
+impl Client {
+    //Connect and wait until the server has taken the connection, so that no data is missed
+    async fn connect(ritual: &Ritual) -> Client {
+        let mut connections = ritual.connections();
+        let stream = TcpStream::connect(ritual.server_address()).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(2), connections.wait_for(|n| *n > 0)).await.unwrap().unwrap();
+        Client { stream, buffer: vec![] }
+    }
+
+    async fn next_message(&mut self) -> Message {
+        let read = async {
+            loop {
+                match Message::decode(&self.buffer) {
+                    Ok((message, size)) => {
+                        self.buffer.drain(..size);
+                        return message;
+                    },
+                    Err(MessageError::Incomplete(_)) => {
+                        let mut chunk = [0; 4096];
+                        let n = self.stream.read(&mut chunk).await.unwrap();
+                        assert!(n > 0, "Server closed the connection");
+                        self.buffer.extend_from_slice(&chunk[..n]);
+                    },
+                    Err(e) => panic!("Bad frame: {}", e)
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), read).await.expect("Timed out waiting for a message")
+    }
+
+    async fn next_of_kind(&mut self, kind: MessageKind) -> Message {
+        loop {
+            let message = self.next_message().await;
+            if message.kind == kind {
+                return message;
+            }
+        }
+    }
+}
+
+fn data_file(project: &Path, run: &str, channel: u16) -> PathBuf {
+    project.join(run).join("UNFILTERED").join(format!("DataR_CH{}@V1730_89_{}.BIN", channel, run))
+}
+
+fn append(path: &Path, bytes: &[u8]) {
+    let mut file = std::fs::OpenOptions::new().create(true).append(true).open(path).unwrap();
+    file.write_all(bytes).unwrap();
+}
+
+fn encode_hits(data_type: CompassDataType, hits: &[CompassHit]) -> Vec<u8> {
+    hits.iter().flat_map(|hit| hit.encode(data_type)).collect()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn streams_a_run_to_clients() {
+    let dir = tempfile::tempdir().unwrap();
+    let project = dir.path();
+    let ritual = Ritual::start(&config(project)).await.unwrap();
+    let mut client = Client::connect(&ritual).await;
+    let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+    let running = tokio::spawn(ritual.run_until(async { _ = shutdown_rx.await; }));
+
+    //CoMPASS creates the run, then the data directory and files
+    std::fs::create_dir_all(project.join("run_1")).unwrap();
+    let start = client.next_of_kind(MessageKind::RunStart).await;
+    assert_eq!(start.run_number, Some(1));
+    assert_eq!(start.source.file, "run_1");
+
+    std::fs::create_dir_all(project.join("run_1").join("UNFILTERED")).unwrap();
+    let data_type = CompassDataType::ENERGY | CompassDataType::ENERGY_SHORT;
+    let hits: Vec<CompassHit> = (0..10)
+        .map(|i| CompassHit { board: 0, channel: 2, timestamp: i * 1000, energy: 500 + i as u16, energy_short: 100, ..Default::default() })
+        .collect();
+    let file = data_file(project, "run_1", 2);
+    append(&file, &data_type.bits().to_le_bytes());
+    let expected = encode_hits(data_type, &hits);
+
+    //Written in two pieces, the first ending part way through a hit
+    append(&file, &expected[..25]);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    append(&file, &expected[25..]);
+
+    let mut received = vec![];
+    while received.len() < expected.len() {
+        let message = client.next_of_kind(MessageKind::Hits).await;
+        assert_eq!(message.run_number, Some(1));
+        assert_eq!(message.source.file, "DataR_CH2@V1730_89_run_1.BIN");
+        assert_eq!(message.source.origin, "UNFILTERED");
+        assert_eq!(message.data_type, data_type.bits());
+        assert_eq!(message.hit_size, data_type.hit_size() as u64);
+        assert_eq!(message.data.len() as u64, message.num_hits * message.hit_size);
+        received.extend_from_slice(&message.data);
+    }
+    assert_eq!(received, expected);
+
+    //The stop file ends the run
+    std::fs::write(project.join("run_1").join("run.info"), "").unwrap();
+    let stop = client.next_of_kind(MessageKind::RunStop).await;
+    let info = RunInfo::decode(&stop.data).unwrap();
+    assert_eq!((info.run_number, info.total_hits), (1, 10));
+    assert!(info.stop_time.is_some());
+
+    shutdown_tx.send(()).unwrap();
+    tokio::time::timeout(Duration::from_secs(5), running).await.unwrap().unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn streams_simulated_runs() {
+    let dir = tempfile::tempdir().unwrap();
+    let project = dir.path().to_path_buf();
+    let ritual = Ritual::start(&config(&project)).await.unwrap();
+    let mut client = Client::connect(&ritual).await;
+    let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+    let running = tokio::spawn(ritual.run_until(async { _ = shutdown_rx.await; }));
+
+    let sim_config: SimulationConfig = serde_yaml::from_str("channels: 3\nrate: 500\nruns: 2\nrun_duration_ms: 200\npause_ms: 100\nflush_interval_ms: 20\n").unwrap();
+    let simulation = tokio::task::spawn_blocking(move || Simulator::new(&project, sim_config).unwrap().run().unwrap());
+
+    for run_number in [1, 2] {
+        assert_eq!(client.next_of_kind(MessageKind::RunStart).await.run_number, Some(run_number));
+        let mut hits = 0;
+        let stop = loop {
+            let message = client.next_message().await;
+            match message.kind {
+                MessageKind::Hits => {
+                    assert_eq!(message.run_number, Some(run_number));
+                    hits += message.num_hits;
+                },
+                MessageKind::RunStop => break RunInfo::decode(&message.data).unwrap(),
+                _ => {}
+            }
+        };
+        assert_eq!(stop.run_number, run_number);
+        assert_eq!(stop.total_hits, hits);
+
+        let run_info = std::fs::read_to_string(dir.path().join(format!("run_{}", run_number)).join("run.info")).unwrap();
+        assert!(run_info.contains(&format!("hits={}\n", hits)), "{} != {}", run_info, hits);
+    }
+
+    assert_eq!(simulation.await.unwrap().len(), 2);
+    shutdown_tx.send(()).unwrap();
+    tokio::time::timeout(Duration::from_secs(5), running).await.unwrap().unwrap();
+}